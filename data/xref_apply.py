@@ -3,8 +3,14 @@ from ghidra.program.model.symbol import SourceType
 import os
 import json
 
+isArm = currentProgram.getLanguage().getProcessor().toString() == "ARM"
+
 def makeSymbol(symbol):
-    addr = toAddr(symbol["offset"])
+    offset = symbol["offset"]
+    # Thumb functions are output with bit 0 set, like function pointers
+    if isArm:
+        offset &= ~1
+    addr = toAddr(offset)
     symbol = symbol["symbol"]
     createLabel(addr, symbol, True, SourceType.USER_DEFINED)
 
@@ -0,0 +1,353 @@
+//! AArch64, decoded with bad64.
+
+use super::{Anchor, Flow, Insn, InstructionSet, Reg, RegSet, Value};
+use crate::error::{trace_bail, TraceResult};
+use crate::ops::{Cond, ZeroCond};
+use crate::relocs::RelocatedMemory;
+use bad64::{Imm, Instruction, Op, Operand, Shift};
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct AArch64;
+
+impl InstructionSet for AArch64 {
+    fn name(&self) -> &'static str {
+        "aarch64"
+    }
+
+    fn decode(&self, memory: &RelocatedMemory, addr: u64) -> TraceResult<Insn> {
+        let data = memory.read_u32(addr)?;
+        let ins = match bad64::decode(data, addr) {
+            Ok(ins) => ins,
+            Err(err) => trace_bail!(DecodeError, "decode error during xref walk: {}", err),
+        };
+        Ok(Insn {
+            addr,
+            len: 4,
+            flow: flow(&ins),
+            def: def(&ins),
+            anchor: (ins.op() == Op::ADRP).then_some(Anchor::Partial),
+            writes: writes(&ins),
+            base: base(&ins),
+            prologue: is_prologue(&ins),
+        })
+    }
+
+    fn reg_name(&self, reg: Reg) -> String {
+        format!("x{}", reg)
+    }
+
+    fn insn_len(&self) -> (u64, u64) {
+        (4, 4)
+    }
+
+    fn is_aligned(&self, addr: u64) -> bool {
+        addr & 0b11 == 0
+    }
+}
+
+/// The number of a general purpose register, treating `wN` and `xN` as the
+/// same register. Returns `None` for the zero register, the stack pointer and
+/// SIMD registers.
+fn reg_index(reg: bad64::Reg) -> Option<Reg> {
+    let name = reg.name();
+    let num = name.strip_prefix('x').or_else(|| name.strip_prefix('w'))?;
+    num.parse().ok().filter(|&num| num <= 30)
+}
+
+fn flow(ins: &Instruction) -> Flow {
+    // Every direct branch has its target as the last operand
+    let target = match ins.operands().last() {
+        Some(Operand::Label(Imm::Unsigned(to))) => Some(*to),
+        _ => None,
+    };
+    let indirect = |call| Flow::Indirect {
+        target: ins
+            .operands()
+            .first()
+            .and_then(operand_reg)
+            .map(|reg| Value::Add(reg, 0)),
+        call,
+    };
+    match (ins.op(), target) {
+        (Op::BL, Some(to)) => Flow::Call(to),
+        (Op::B, Some(to)) => Flow::Branch(to),
+        (Op::CBZ, Some(to)) => Flow::CompareBranch(ZeroCond::Zero, to),
+        (Op::CBNZ, Some(to)) => Flow::CompareBranch(ZeroCond::NonZero, to),
+        (Op::TBZ, Some(to)) => Flow::TestBranch(ZeroCond::Zero, to),
+        (Op::TBNZ, Some(to)) => Flow::TestBranch(ZeroCond::NonZero, to),
+        (Op::BLR, _) => indirect(true),
+        (Op::BR, _) => indirect(false),
+        (Op::RET, _) => Flow::Return,
+        (op, Some(to)) => match branch_cond(op) {
+            Some(cond) => Flow::CondBranch(cond, to),
+            None => Flow::Next,
+        },
+        _ => Flow::Next,
+    }
+}
+
+/// The condition of a `B.cond` instruction
+fn branch_cond(op: Op) -> Option<Cond> {
+    Some(match op {
+        Op::B_EQ => Cond::Eq,
+        Op::B_NE => Cond::Ne,
+        Op::B_CS => Cond::Cs,
+        Op::B_CC => Cond::Cc,
+        Op::B_MI => Cond::Mi,
+        Op::B_PL => Cond::Pl,
+        Op::B_VS => Cond::Vs,
+        Op::B_VC => Cond::Vc,
+        Op::B_HI => Cond::Hi,
+        Op::B_LS => Cond::Ls,
+        Op::B_GE => Cond::Ge,
+        Op::B_LT => Cond::Lt,
+        Op::B_GT => Cond::Gt,
+        Op::B_LE => Cond::Le,
+        _ => return None,
+    })
+}
+
+/// The register `ins` sets from the `ADRP`, `ADR`, `ADD`, `LDR` and `MOV`
+/// forms that can be followed
+fn def(ins: &Instruction) -> Option<(Reg, Value)> {
+    let dest = ins.operands().first().and_then(operand_reg)?;
+    let value = match (ins.op(), ins.operands()) {
+        (Op::ADRP | Op::ADR, [_, Operand::Label(Imm::Unsigned(imm))]) => Value::Const(*imm),
+        (Op::ADD, [_, Operand::Reg { reg: src, .. }, Operand::Imm64 { imm, shift }]) => {
+            Value::Add(reg_index(*src)?, shifted_imm(*imm, *shift)?)
+        }
+        (
+            Op::LDR,
+            [_, Operand::MemOffset {
+                reg: src, offset, ..
+            }],
+        ) => Value::Load(reg_index(*src)?, imm_value(*offset)),
+        (Op::LDR, [_, Operand::MemReg(src)]) => Value::Load(reg_index(*src)?, 0),
+        (Op::MOV, [_, Operand::Reg { reg: src, .. }]) => Value::Add(reg_index(*src)?, 0),
+        _ => return None,
+    };
+    Some((dest, value))
+}
+
+/// Every register `ins` leaves with a new value
+fn writes(ins: &Instruction) -> RegSet {
+    let mut writes: RegSet = match ins.op() {
+        // Calls clobber the link register and every caller-saved register
+        Op::BL | Op::BLR => (0..=18).chain([30]).collect(),
+        op if !has_dest(op) => RegSet::default(),
+        Op::LDP | Op::LDPSW | Op::LDNP => ins
+            .operands()
+            .iter()
+            .take(2)
+            .filter_map(operand_reg)
+            .collect(),
+        _ => ins
+            .operands()
+            .first()
+            .and_then(operand_reg)
+            .into_iter()
+            .collect(),
+    };
+    // Pre and post-indexed loads and stores write the address back to their
+    // base register
+    for operand in ins.operands() {
+        let base = match *operand {
+            Operand::MemPreIdx { reg, .. } | Operand::MemPostIdxImm { reg, .. } => reg,
+            Operand::MemPostIdxReg([reg, _]) => reg,
+            _ => continue,
+        };
+        if let Some(base) = reg_index(base) {
+            writes.insert(base);
+        }
+    }
+    writes
+}
+
+/// The register `ins` uses as the base of an address, either as the base
+/// register of a load or store or as the source of an `ADD` immediate, and
+/// the offset it adds.
+fn base(ins: &Instruction) -> Option<(Reg, i64)> {
+    match (ins.op(), ins.operands()) {
+        (Op::ADD, [_, Operand::Reg { reg: src, .. }, Operand::Imm64 { imm, shift }]) => {
+            Some((reg_index(*src)?, shifted_imm(*imm, *shift)?))
+        }
+        (Op::ADD, _) => None,
+        _ => ins.operands().iter().find_map(|operand| match *operand {
+            Operand::MemReg(base) => Some((reg_index(base)?, 0)),
+            Operand::MemOffset {
+                reg: base, offset, ..
+            } => Some((reg_index(base)?, imm_value(offset))),
+            Operand::MemPreIdx { reg: base, imm } => Some((reg_index(base)?, imm_value(imm))),
+            _ => None,
+        }),
+    }
+}
+
+/// Whether `ins` looks like the first instruction of a function: a pointer
+/// authentication or branch target hint, or making room on the stack.
+fn is_prologue(ins: &Instruction) -> bool {
+    matches!(
+        (ins.op(), ins.operands()),
+        (Op::PACIASP | Op::PACIBSP | Op::BTI, _)
+            | (
+                Op::SUB,
+                [
+                    Operand::Reg {
+                        reg: bad64::Reg::SP,
+                        ..
+                    },
+                    Operand::Reg {
+                        reg: bad64::Reg::SP,
+                        ..
+                    },
+                    ..
+                ]
+            )
+            | (
+                Op::STP | Op::STR,
+                [
+                    ..,
+                    Operand::MemPreIdx {
+                        reg: bad64::Reg::SP,
+                        ..
+                    }
+                ]
+            )
+    )
+}
+
+fn imm_value(imm: Imm) -> i64 {
+    match imm {
+        Imm::Signed(imm) => imm,
+        Imm::Unsigned(imm) => imm as i64,
+    }
+}
+
+/// An immediate with its optional `lsl #12`, or `None` for shifts an `ADD`
+/// cannot have
+fn shifted_imm(imm: Imm, shift: Option<Shift>) -> Option<i64> {
+    match shift {
+        None => Some(imm_value(imm)),
+        Some(Shift::LSL(amount)) => Some(imm_value(imm) << amount),
+        Some(_) => None,
+    }
+}
+
+fn operand_reg(operand: &Operand) -> Option<Reg> {
+    match *operand {
+        Operand::Reg { reg, .. } => reg_index(reg),
+        _ => None,
+    }
+}
+
+/// Whether the first operand of `op` is a destination register
+fn has_dest(op: Op) -> bool {
+    !matches!(
+        op,
+        Op::STR
+            | Op::STRB
+            | Op::STRH
+            | Op::STUR
+            | Op::STURB
+            | Op::STURH
+            | Op::STP
+            | Op::STNP
+            | Op::STLR
+            | Op::STLRB
+            | Op::STLRH
+            | Op::CMP
+            | Op::CMN
+            | Op::TST
+            | Op::CCMP
+            | Op::CCMN
+            | Op::CBZ
+            | Op::CBNZ
+            | Op::TBZ
+            | Op::TBNZ
+            | Op::B
+            | Op::BR
+            | Op::RET
+            | Op::PRFM
+            | Op::PRFUM
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::FailureKind;
+    use crate::memory::AddressSpace;
+    use crate::tracer::tests::trace;
+    use std::collections::HashMap;
+
+    /// Forms addresses in the page at 0x3000 with `ADRP`, and calls the
+    /// function pointer at 0x3020
+    const CODE: &[u8] = &[
+        0x00, 0x00, 0x00, 0xd0, // 1000: adrp x0, 0x3000
+        0x00, 0x40, 0x00, 0x91, // 1004: add x0, x0, #0x10
+        0x01, 0x04, 0x40, 0xf9, // 1008: ldr x1, [x0, #8]
+        0x10, 0x00, 0x00, 0xd0, // 100c: adrp x16, 0x3000
+        0x11, 0x12, 0x40, 0xf9, // 1010: ldr x17, [x16, #0x20]
+        0x20, 0x02, 0x3f, 0xd6, // 1014: blr x17
+        0x42, 0x04, 0x40, 0x91, // 1018: add x2, x2, #1, lsl #12
+        0x83, 0x0c, 0x41, 0xf8, // 101c: ldr x3, [x4, #16]!
+        0x83, 0x04, 0x41, 0xf8, // 1020: ldr x3, [x4], #16
+        0xc0, 0x03, 0x5f, 0xd6, // 1024: ret
+        0xc0, 0x03, 0x5f, 0xd6, // 1028: ret
+    ];
+
+    fn decode(addr: u64) -> Insn {
+        let memory = RelocatedMemory::from_raw(
+            AddressSpace::from_raw(&[(0x1000, CODE)]),
+            8,
+            HashMap::new(),
+            HashMap::new(),
+        );
+        AArch64.decode(&memory, addr).unwrap()
+    }
+
+    fn trace_code(ops: &str) -> Result<u64, FailureKind> {
+        // The function pointer at 0x3020 is the `ret` at 0x1028
+        let mut data = [0; 0x28];
+        data[0x20..].copy_from_slice(&0x1028u64.to_le_bytes());
+        trace(
+            Box::new(AArch64),
+            8,
+            &[(0x1000, CODE), (0x3000, &data)],
+            &[0x1000..0x1028, 0x1028..0x102c],
+            "addr:0x1000",
+            ops,
+        )
+    }
+
+    #[test]
+    fn decodes_address_forming_instructions() {
+        let adrp = decode(0x1000);
+        assert_eq!(adrp.def, Some((0, Value::Const(0x3000))));
+        assert_eq!(adrp.anchor, Some(Anchor::Partial));
+        assert_eq!(decode(0x1004).base, Some((0, 0x10)));
+        assert_eq!(decode(0x1008).def, Some((1, Value::Load(0, 8))));
+        let shifted = decode(0x1018);
+        assert_eq!(shifted.def, Some((2, Value::Add(2, 0x1000))));
+        assert_eq!(shifted.base, Some((2, 0x1000)));
+    }
+
+    #[test]
+    fn indexed_loads_write_back_their_base() {
+        let pre = decode(0x101c);
+        assert!(pre.writes.contains(3) && pre.writes.contains(4));
+        assert_eq!(pre.base, Some((4, 16)));
+        let post = decode(0x1020);
+        assert!(post.writes.contains(3) && post.writes.contains(4));
+        assert_eq!(post.base, None);
+    }
+
+    #[test]
+    fn traces_adrp_users_and_calls_through_them() {
+        assert_eq!(trace_code("P0"), Ok(0x3010));
+        assert_eq!(trace_code("P0.1"), Ok(0x3018));
+        assert_eq!(trace_code("P1"), Ok(0x3020));
+        assert_eq!(trace_code("P1D"), Ok(0x1028));
+        assert_eq!(trace_code("R0"), Ok(0x1028));
+    }
+}
@@ -0,0 +1,566 @@
+//! 32-bit ARM and Thumb-2, decoded with capstone.
+//!
+//! Addresses keep the instruction set in bit 0 the way function pointers and
+//! `BX` do: odd addresses are Thumb code at the address with bit 0 cleared,
+//! even ones are ARM code.
+
+use super::{Anchor, Flow, Insn, InstructionSet, Reg, RegSet, Value};
+use crate::error::{trace_bail, TraceResult};
+use crate::ops::{Cond, ZeroCond};
+use crate::relocs::RelocatedMemory;
+use capstone::arch::arm::{ArchMode, ArmCC, ArmInsn, ArmOperand, ArmOperandType, ArmReg};
+use capstone::arch::ArchDetail;
+use capstone::prelude::*;
+use capstone::RegId;
+
+const SP: Reg = 13;
+const LR: Reg = 14;
+
+thread_local! {
+    // Capstone handles cannot be shared between threads
+    static THUMB: Capstone = decoder(ArchMode::Thumb);
+    static ARM: Capstone = decoder(ArchMode::Arm);
+}
+
+fn decoder(mode: ArchMode) -> Capstone {
+    Capstone::new()
+        .arm()
+        .mode(mode)
+        .detail(true)
+        .build()
+        .expect("capstone was built with ARM support")
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ArmV7;
+
+impl InstructionSet for ArmV7 {
+    fn name(&self) -> &'static str {
+        "armv7"
+    }
+
+    fn decode(&self, memory: &RelocatedMemory, addr: u64) -> TraceResult<Insn> {
+        let thumb = addr & 1 != 0;
+        let at = addr & !1;
+        // A 16-bit Thumb instruction may be the last thing in its segment
+        let data = match memory.read(at, 4) {
+            Ok(data) => data,
+            Err(_) if thumb => memory.read(at, 2)?,
+            Err(err) => return Err(err.into()),
+        };
+        let decoder = if thumb { &THUMB } else { &ARM };
+        decoder.with(|cs| {
+            let insns = match cs.disasm_count(data, at, 1) {
+                Ok(insns) => insns,
+                Err(err) => trace_bail!(DecodeError, "decode error during xref walk: {}", err),
+            };
+            let insn = match insns.iter().next() {
+                Some(insn) => insn,
+                None => trace_bail!(DecodeError, "invalid instruction at {:#x}", addr),
+            };
+            let detail = match cs.insn_detail(&insn) {
+                Ok(detail) => detail,
+                Err(err) => trace_bail!(DecodeError, "decode error during xref walk: {}", err),
+            };
+            let arm = match detail.arch_detail() {
+                ArchDetail::ArmDetail(arm) => arm,
+                _ => trace_bail!(DecodeError, "capstone returned a non-ARM instruction"),
+            };
+            // Capstone gives `MOVW` the id of `MOV`, which cannot start an
+            // address
+            let id = match insn.mnemonic() {
+                Some("movw") => ARM_INS_MOVW,
+                _ => insn.id().0,
+            };
+            let decoded = Decoded {
+                id,
+                addr,
+                thumb,
+                operands: arm.operands().collect(),
+                cc: arm.cc(),
+                writeback: arm.writeback(),
+            };
+            let mut writes = decoded.writes();
+            for reg in detail.regs_write() {
+                if let Some(reg) = reg_index(reg) {
+                    writes.insert(reg);
+                }
+            }
+            Ok(Insn {
+                addr,
+                len: insn.bytes().len() as u8,
+                flow: decoded.flow(),
+                def: decoded.def(),
+                anchor: decoded.anchor(),
+                writes,
+                base: decoded.base(),
+                prologue: decoded.prologue(),
+            })
+        })
+    }
+
+    fn reg_name(&self, reg: Reg) -> String {
+        match reg {
+            SP => "sp".to_owned(),
+            LR => "lr".to_owned(),
+            reg => format!("r{}", reg),
+        }
+    }
+
+    fn insn_len(&self) -> (u64, u64) {
+        (2, 4)
+    }
+
+    fn is_aligned(&self, addr: u64) -> bool {
+        // Any odd address is a 2-byte aligned Thumb instruction
+        addr & 1 != 0 || addr & 0b11 == 0
+    }
+
+    fn code_addr(&self, addr: u64) -> u64 {
+        addr & !1
+    }
+}
+
+/// The number of a core register, or `None` for the program counter and
+/// every other register
+fn reg_index(reg: RegId) -> Option<Reg> {
+    let reg = reg.0 as u32;
+    if (ArmReg::ARM_REG_R0..=ArmReg::ARM_REG_R12).contains(&reg) {
+        return Some((reg - ArmReg::ARM_REG_R0) as Reg);
+    }
+    match reg {
+        ArmReg::ARM_REG_SP => Some(SP),
+        ArmReg::ARM_REG_LR => Some(LR),
+        _ => None,
+    }
+}
+
+fn is_pc(reg: RegId) -> bool {
+    reg.0 as u32 == ArmReg::ARM_REG_PC
+}
+
+macro_rules! insn_ids {
+    ($($name:ident),* $(,)?) => {
+        $(const $name: u32 = ArmInsn::$name as u32;)*
+    };
+}
+
+insn_ids!(
+    ARM_INS_ADD,
+    ARM_INS_B,
+    ARM_INS_BL,
+    ARM_INS_BLX,
+    ARM_INS_BX,
+    ARM_INS_CBNZ,
+    ARM_INS_CBZ,
+    ARM_INS_CMN,
+    ARM_INS_CMP,
+    ARM_INS_IT,
+    ARM_INS_LDM,
+    ARM_INS_LDMDA,
+    ARM_INS_LDMDB,
+    ARM_INS_LDMIB,
+    ARM_INS_LDR,
+    ARM_INS_LDRD,
+    ARM_INS_MOV,
+    ARM_INS_MOVT,
+    ARM_INS_MOVW,
+    ARM_INS_NOP,
+    ARM_INS_PLD,
+    ARM_INS_POP,
+    ARM_INS_PUSH,
+    ARM_INS_STM,
+    ARM_INS_STMDA,
+    ARM_INS_STMDB,
+    ARM_INS_STMIB,
+    ARM_INS_STR,
+    ARM_INS_STRB,
+    ARM_INS_STRD,
+    ARM_INS_STRH,
+    ARM_INS_SUB,
+    ARM_INS_TBB,
+    ARM_INS_TBH,
+    ARM_INS_TEQ,
+    ARM_INS_TST,
+);
+
+/// The parts of capstone's instruction detail the tracer needs
+struct Decoded {
+    id: u32,
+    /// The address of the instruction, with bit 0 set for Thumb
+    addr: u64,
+    thumb: bool,
+    operands: Vec<ArmOperand>,
+    cc: ArmCC,
+    writeback: bool,
+}
+
+impl Decoded {
+    /// The value the program counter reads as in this instruction
+    fn pc(&self) -> u64 {
+        if self.thumb {
+            (self.addr & !1) + 4
+        } else {
+            self.addr + 8
+        }
+    }
+
+    /// The program counter as used by literal loads and `ADR`, which align
+    /// it down to a word first
+    fn aligned_pc(&self) -> u64 {
+        self.pc() & !0b11
+    }
+
+    /// A branch target from an immediate operand, in the instruction set of
+    /// this instruction unless `switch` is set
+    fn target(&self, imm: i32, switch: bool) -> u64 {
+        let to = imm as u32 as u64;
+        if self.thumb != switch {
+            to | 1
+        } else {
+            to
+        }
+    }
+
+    fn op(&self, idx: usize) -> Option<&ArmOperandType> {
+        self.operands.get(idx).map(|op| &op.op_type)
+    }
+
+    fn reg(&self, idx: usize) -> Option<RegId> {
+        match self.op(idx) {
+            Some(ArmOperandType::Reg(reg)) => Some(*reg),
+            _ => None,
+        }
+    }
+
+    fn list_has_pc(&self) -> bool {
+        self.operands
+            .iter()
+            .any(|op| matches!(op.op_type, ArmOperandType::Reg(reg) if is_pc(reg)))
+    }
+
+    fn flow(&self) -> Flow {
+        let conditional = !matches!(self.cc, ArmCC::ARM_CC_AL | ArmCC::ARM_CC_INVALID);
+        match (self.id, self.op(0), self.op(1)) {
+            (ARM_INS_B, Some(&ArmOperandType::Imm(imm)), _) => {
+                let to = self.target(imm, false);
+                match cond(self.cc) {
+                    Some(cond) if conditional => Flow::CondBranch(cond, to),
+                    _ => Flow::Branch(to),
+                }
+            }
+            (ARM_INS_BL, Some(&ArmOperandType::Imm(imm)), _) => Flow::Call(self.target(imm, false)),
+            (ARM_INS_BLX, Some(&ArmOperandType::Imm(imm)), _) => Flow::Call(self.target(imm, true)),
+            (ARM_INS_BLX, Some(&ArmOperandType::Reg(reg)), _) => Flow::Indirect {
+                target: reg_index(reg).map(|reg| Value::Add(reg, 0)),
+                call: true,
+            },
+            (ARM_INS_BX, Some(&ArmOperandType::Reg(reg)), _) => match reg_index(reg) {
+                Some(LR) => Flow::Return,
+                reg => Flow::Indirect {
+                    target: reg.map(|reg| Value::Add(reg, 0)),
+                    call: false,
+                },
+            },
+            (ARM_INS_CBZ, _, Some(&ArmOperandType::Imm(imm))) => {
+                Flow::CompareBranch(ZeroCond::Zero, self.target(imm, false))
+            }
+            (ARM_INS_CBNZ, _, Some(&ArmOperandType::Imm(imm))) => {
+                Flow::CompareBranch(ZeroCond::NonZero, self.target(imm, false))
+            }
+            (ARM_INS_POP | ARM_INS_LDM | ARM_INS_LDMDA | ARM_INS_LDMDB | ARM_INS_LDMIB, _, _)
+                if self.list_has_pc() =>
+            {
+                Flow::Return
+            }
+            (ARM_INS_MOV, Some(&ArmOperandType::Reg(dest)), Some(&ArmOperandType::Reg(src)))
+                if is_pc(dest) =>
+            {
+                match reg_index(src) {
+                    Some(LR) => Flow::Return,
+                    reg => Flow::Indirect {
+                        target: reg.map(|reg| Value::Add(reg, 0)),
+                        call: false,
+                    },
+                }
+            }
+            // Jump tables and loads into the program counter go somewhere
+            // that cannot be known from the instruction
+            (ARM_INS_TBB | ARM_INS_TBH, _, _) => Flow::Indirect {
+                target: None,
+                call: false,
+            },
+            (ARM_INS_LDR, Some(&ArmOperandType::Reg(dest)), _) if is_pc(dest) => Flow::Indirect {
+                target: None,
+                call: false,
+            },
+            _ => Flow::Next,
+        }
+    }
+
+    /// The register this instruction sets from the `MOVW`, `MOVT`, `MOV`,
+    /// `ADD`, `SUB` and `LDR` forms that can be followed
+    fn def(&self) -> Option<(Reg, Value)> {
+        let dest = reg_index(self.reg(0)?)?;
+        let value = match (self.id, self.op(1), self.op(2)) {
+            (ARM_INS_MOVW, Some(&ArmOperandType::Imm(imm)), None) => {
+                Value::Const(imm as u32 as u16 as u64)
+            }
+            (ARM_INS_MOVT, Some(&ArmOperandType::Imm(imm)), None) => {
+                Value::InsertHigh(imm as u32 as u16)
+            }
+            (ARM_INS_MOV, Some(&ArmOperandType::Imm(imm)), None) => Value::Const(imm as u32 as u64),
+            (ARM_INS_MOV, Some(&ArmOperandType::Reg(src)), None) => match reg_index(src) {
+                Some(src) => Value::Add(src, 0),
+                None if is_pc(src) => Value::Const(self.pc()),
+                None => return None,
+            },
+            (ARM_INS_ADD | ARM_INS_SUB, ..) => {
+                let (src, offset) = self.add_operands()?;
+                match src {
+                    Some(src) => Value::Add(src, offset),
+                    None => Value::Const(offset as u64),
+                }
+            }
+            (ARM_INS_LDR, Some(ArmOperandType::Mem(mem)), _) => {
+                let (base, index) = (mem.base(), mem.index());
+                let disp = self.disp(mem.disp());
+                if is_pc(base) && index.0 == 0 {
+                    Value::LoadFrom(self.aligned_pc().wrapping_add(disp as u64))
+                } else if is_pc(base) {
+                    Value::Load(reg_index(index)?, self.pc() as i64)
+                } else if index.0 == 0 {
+                    Value::Load(reg_index(base)?, disp)
+                } else {
+                    return None;
+                }
+            }
+            _ => return None,
+        };
+        Some((dest, value))
+    }
+
+    /// The register and offset an `ADD` or `SUB` adds together, counting the
+    /// program counter as an offset. The register is `None` if the program
+    /// counter was added to an immediate.
+    fn add_operands(&self) -> Option<(Option<Reg>, i64)> {
+        let sign = if self.id == ARM_INS_SUB { -1 } else { 1 };
+        // Two operand forms such as `add r0, pc` use the destination as the
+        // first source
+        let (first, second) = match (self.op(2), self.op(1)) {
+            (Some(second), Some(&ArmOperandType::Reg(first))) => (first, second),
+            (None, Some(second)) => (self.reg(0)?, second),
+            _ => return None,
+        };
+        match *second {
+            ArmOperandType::Imm(imm) if is_pc(first) => {
+                Some((None, self.aligned_pc() as i64 + sign * imm as i64))
+            }
+            ArmOperandType::Imm(imm) => Some((Some(reg_index(first)?), sign * imm as i64)),
+            ArmOperandType::Reg(second) if sign == 1 && is_pc(second) => {
+                Some((Some(reg_index(first)?), self.pc() as i64))
+            }
+            ArmOperandType::Reg(second) if sign == 1 && is_pc(first) => {
+                Some((Some(reg_index(second)?), self.pc() as i64))
+            }
+            _ => None,
+        }
+    }
+
+    fn anchor(&self) -> Option<Anchor> {
+        match (self.id, self.op(1)) {
+            (ARM_INS_MOVW, _) => Some(Anchor::Partial),
+            (ARM_INS_LDR, Some(ArmOperandType::Mem(mem))) if is_pc(mem.base()) => {
+                Some(Anchor::Partial)
+            }
+            _ => None,
+        }
+    }
+
+    /// The register this instruction uses as the base of an address, either
+    /// as the base or index of a load or store or as the source of an `ADD`
+    /// or `SUB`, and the offset it adds
+    fn base(&self) -> Option<(Reg, i64)> {
+        if matches!(self.id, ARM_INS_ADD | ARM_INS_SUB) {
+            return match self.add_operands()? {
+                (Some(src), offset) => Some((src, offset)),
+                (None, _) => None,
+            };
+        }
+        self.operands.iter().find_map(|op| match &op.op_type {
+            ArmOperandType::Mem(mem) if is_pc(mem.base()) && mem.index().0 != 0 => {
+                Some((reg_index(mem.index())?, self.pc() as i64))
+            }
+            ArmOperandType::Mem(mem) if mem.index().0 == 0 => {
+                Some((reg_index(mem.base())?, self.disp(mem.disp())))
+            }
+            _ => None,
+        })
+    }
+
+    /// A memory displacement, which capstone gives as a magnitude for
+    /// subtracted offsets
+    fn disp(&self, disp: i32) -> i64 {
+        let subtracted = self.operands.iter().any(|op| op.subtracted);
+        if subtracted && disp > 0 {
+            -(disp as i64)
+        } else {
+            disp as i64
+        }
+    }
+
+    /// Every register this instruction leaves with a new value, apart from
+    /// the implicit ones capstone lists
+    fn writes(&self) -> RegSet {
+        let regs = |ops: &[ArmOperand]| -> RegSet {
+            ops.iter()
+                .filter_map(|op| match op.op_type {
+                    ArmOperandType::Reg(reg) => reg_index(reg),
+                    _ => None,
+                })
+                .collect()
+        };
+        let mut writes = match self.id {
+            // Calls clobber the link register and every caller-saved register
+            ARM_INS_BL | ARM_INS_BLX => [0, 1, 2, 3, 12, LR].into_iter().collect(),
+            ARM_INS_POP => regs(&self.operands),
+            ARM_INS_LDM | ARM_INS_LDMDA | ARM_INS_LDMDB | ARM_INS_LDMIB => {
+                regs(self.operands.get(1..).unwrap_or_default())
+            }
+            ARM_INS_LDRD => regs(&self.operands[..self.operands.len().min(2)]),
+            ARM_INS_STR | ARM_INS_STRB | ARM_INS_STRH | ARM_INS_STRD | ARM_INS_STM
+            | ARM_INS_STMDA | ARM_INS_STMDB | ARM_INS_STMIB | ARM_INS_PUSH | ARM_INS_CMP
+            | ARM_INS_CMN | ARM_INS_TST | ARM_INS_TEQ | ARM_INS_B | ARM_INS_BX | ARM_INS_CBZ
+            | ARM_INS_CBNZ | ARM_INS_TBB | ARM_INS_TBH | ARM_INS_IT | ARM_INS_NOP | ARM_INS_PLD => {
+                RegSet::default()
+            }
+            _ => self.reg(0).and_then(reg_index).into_iter().collect(),
+        };
+        if self.writeback {
+            let base = self.operands.iter().find_map(|op| match &op.op_type {
+                ArmOperandType::Mem(mem) => reg_index(mem.base()),
+                _ => None,
+            });
+            // Load and store multiple write back to their first operand
+            if let Some(base) = base.or_else(|| self.reg(0).and_then(reg_index)) {
+                writes.insert(base);
+            }
+        }
+        writes
+    }
+
+    /// Whether this saves registers or makes room on the stack
+    fn prologue(&self) -> bool {
+        let is_sp = |idx| self.reg(idx).and_then(reg_index) == Some(SP);
+        match self.id {
+            ARM_INS_PUSH => true,
+            ARM_INS_STMDB => is_sp(0) && self.writeback,
+            ARM_INS_SUB => is_sp(0) && (is_sp(1) || self.operands.len() == 2),
+            _ => false,
+        }
+    }
+}
+
+/// The condition a branch is taken on, with the unsigned comparisons under
+/// their carry flag names
+fn cond(cc: ArmCC) -> Option<Cond> {
+    Some(match cc {
+        ArmCC::ARM_CC_EQ => Cond::Eq,
+        ArmCC::ARM_CC_NE => Cond::Ne,
+        ArmCC::ARM_CC_HS => Cond::Cs,
+        ArmCC::ARM_CC_LO => Cond::Cc,
+        ArmCC::ARM_CC_MI => Cond::Mi,
+        ArmCC::ARM_CC_PL => Cond::Pl,
+        ArmCC::ARM_CC_VS => Cond::Vs,
+        ArmCC::ARM_CC_VC => Cond::Vc,
+        ArmCC::ARM_CC_HI => Cond::Hi,
+        ArmCC::ARM_CC_LS => Cond::Ls,
+        ArmCC::ARM_CC_GE => Cond::Ge,
+        ArmCC::ARM_CC_LT => Cond::Lt,
+        ArmCC::ARM_CC_GT => Cond::Gt,
+        ArmCC::ARM_CC_LE => Cond::Le,
+        _ => return None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::FailureKind;
+    use crate::memory::AddressSpace;
+    use crate::tracer::tests::trace;
+    use std::collections::HashMap;
+
+    /// Thumb code forming 0x13000 with `MOVW`/`MOVT` and 0x13004 with a
+    /// literal, each added to the program counter, then calling the function
+    /// pointer at 0x13004
+    const THUMB: &[u8] = &[
+        0x41, 0xf6, 0xf4, 0x70, // 1000: movw r0, #0x1ff4
+        0xc0, 0xf2, 0x01, 0x00, // 1004: movt r0, #0x1
+        0x78, 0x44, // 1008: add r0, pc
+        0x01, 0x68, // 100a: ldr r1, [r0]
+        0x02, 0x4a, // 100c: ldr r2, [pc, #8]
+        0x7a, 0x44, // 100e: add r2, pc
+        0x13, 0x68, // 1010: ldr r3, [r2]
+        0x98, 0x47, // 1012: blx r3
+        0x70, 0x47, // 1014: bx lr
+        0x00, 0xbf, // 1016: nop
+        0xf2, 0x1f, 0x01, 0x00, // 1018: .word 0x11ff2
+    ];
+
+    fn decode(addr: u64) -> Insn {
+        let memory = RelocatedMemory::from_raw(
+            AddressSpace::from_raw(&[(0x1000, THUMB)]),
+            4,
+            HashMap::new(),
+            HashMap::new(),
+        );
+        ArmV7.decode(&memory, addr).unwrap()
+    }
+
+    fn trace_thumb(ops: &str) -> Result<u64, FailureKind> {
+        // The function pointer at 0x13004 is the Thumb `nop` at 0x1016
+        let data = [0, 0, 0, 0, 0x17, 0x10, 0, 0];
+        let function = 0x1000..0x1018;
+        trace(
+            Box::new(ArmV7),
+            4,
+            &[(0x1000, THUMB), (0x13000, &data)],
+            &[function],
+            "addr:0x1001",
+            ops,
+        )
+    }
+
+    #[test]
+    fn decodes_addresses_relative_to_the_thumb_pc() {
+        let movw = decode(0x1001);
+        assert_eq!(movw.def, Some((0, Value::Const(0x1ff4))));
+        assert_eq!(movw.anchor, Some(Anchor::Partial));
+        assert_eq!(decode(0x1005).def, Some((0, Value::InsertHigh(1))));
+        let add = decode(0x1009);
+        assert_eq!(add.def, Some((0, Value::Add(0, 0x100c))));
+        assert_eq!(add.base, Some((0, 0x100c)));
+        let literal = decode(0x100d);
+        assert_eq!(literal.def, Some((2, Value::LoadFrom(0x1018))));
+        assert_eq!(literal.anchor, Some(Anchor::Partial));
+        assert_eq!(decode(0x100f).base, Some((2, 0x1012)));
+        assert_eq!(
+            decode(0x1013).flow,
+            Flow::Indirect {
+                target: Some(Value::Add(3, 0)),
+                call: true
+            }
+        );
+        assert_eq!(decode(0x1015).flow, Flow::Return);
+    }
+
+    #[test]
+    fn traces_addresses_added_to_the_pc() {
+        assert_eq!(trace_thumb("P0"), Ok(0x13000));
+        assert_eq!(trace_thumb("P0.1"), Ok(0x13000));
+        assert_eq!(trace_thumb("P1"), Ok(0x13004));
+        assert_eq!(trace_thumb("P1.1"), Ok(0x13004));
+        assert_eq!(trace_thumb("R0"), Ok(0x1017));
+    }
+}
@@ -0,0 +1,154 @@
+//! The instruction sets xref traces can walk.
+//!
+//! Each architecture decodes its instructions into an [`Insn`], which only
+//! describes what trace ops look at: where control flow goes, which register
+//! an instruction sets and how, and which registers it uses as the base of an
+//! address. The tracer only ever looks at [`Insn`]s, so every trace op works
+//! the same way on every architecture.
+
+mod aarch64;
+mod armv7;
+mod x86;
+
+pub use aarch64::AArch64;
+pub use armv7::ArmV7;
+pub use x86::X86;
+
+use crate::error::TraceResult;
+use crate::ops::{Cond, ZeroCond};
+use crate::relocs::RelocatedMemory;
+use color_eyre::eyre::{bail, Result};
+use std::fmt;
+
+/// A general purpose register, numbered by its architecture
+pub type Reg = u8;
+
+/// A set of general purpose registers
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RegSet(u64);
+
+impl RegSet {
+    pub fn insert(&mut self, reg: Reg) {
+        self.0 |= 1 << reg;
+    }
+
+    pub fn contains(self, reg: Reg) -> bool {
+        self.0 & (1 << reg) != 0
+    }
+}
+
+impl FromIterator<Reg> for RegSet {
+    fn from_iter<I: IntoIterator<Item = Reg>>(iter: I) -> Self {
+        let mut set = RegSet::default();
+        for reg in iter {
+            set.insert(reg);
+        }
+        set
+    }
+}
+
+/// Where control goes after an instruction
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Flow {
+    /// On to the next instruction
+    Next,
+    /// A direct call (`BL`)
+    Call(u64),
+    /// A direct unconditional branch (`B`)
+    Branch(u64),
+    /// A direct branch on a condition flag (`B.cond`)
+    CondBranch(Cond, u64),
+    /// A branch on whether a register is zero (`CBZ`/`CBNZ`)
+    CompareBranch(ZeroCond, u64),
+    /// A branch on whether a bit of a register is zero (`TBZ`/`TBNZ`)
+    TestBranch(ZeroCond, u64),
+    /// A call or branch to the address in a register (`BLR`/`BR`), or to a
+    /// pointer in memory. `target` is `None` if it cannot be tracked.
+    Indirect { target: Option<Value>, call: bool },
+    /// A return from the function
+    Return,
+}
+
+/// How an instruction computes the value it writes to a register
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Value {
+    /// A value known from the instruction alone
+    Const(u64),
+    /// The value of a register plus an offset
+    Add(Reg, i64),
+    /// The low half of the register's previous value with these high 16 bits
+    /// (`MOVT`)
+    InsertHigh(u16),
+    /// The pointer stored at the value of a register plus an offset
+    Load(Reg, i64),
+    /// The pointer stored at a known address
+    LoadFrom(u64),
+}
+
+/// An instruction that starts forming an address, counted by `P`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Anchor {
+    /// The value written still needs to be combined with its users, as with
+    /// `ADRP` or `MOVW`
+    Partial,
+    /// The instruction forms the full address by itself
+    Complete(u64),
+}
+
+/// A decoded instruction, reduced to what trace ops need to know about it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Insn {
+    pub addr: u64,
+    pub len: u8,
+    pub flow: Flow,
+    /// The register this instruction sets and how, if the value can be
+    /// followed
+    pub def: Option<(Reg, Value)>,
+    pub anchor: Option<Anchor>,
+    /// Every register left with a new value, including those clobbered by
+    /// calls
+    pub writes: RegSet,
+    /// A register used as the base of an address and the offset added to it
+    pub base: Option<(Reg, i64)>,
+    /// Whether the instruction looks like the first one of a function
+    pub prologue: bool,
+}
+
+impl Insn {
+    /// The address of the instruction after this one
+    pub fn next(&self) -> u64 {
+        self.addr + self.len as u64
+    }
+}
+
+/// An instruction set the tracer can walk
+pub trait InstructionSet: fmt::Debug + Send + Sync {
+    fn name(&self) -> &'static str;
+
+    fn decode(&self, memory: &RelocatedMemory, addr: u64) -> TraceResult<Insn>;
+
+    fn reg_name(&self, reg: Reg) -> String;
+
+    /// The shortest and longest instruction, in bytes
+    fn insn_len(&self) -> (u64, u64);
+
+    /// Whether `addr` is aligned for the instruction set it selects
+    fn is_aligned(&self, addr: u64) -> bool;
+
+    /// The address of the instruction at `addr`, without any bits that select
+    /// the instruction set
+    fn code_addr(&self, addr: u64) -> u64 {
+        addr
+    }
+}
+
+/// The instruction set of shared objects for `machine`
+pub fn for_machine(machine: object::Architecture) -> Result<Box<dyn InstructionSet>> {
+    Ok(match machine {
+        object::Architecture::Aarch64 => Box::new(AArch64),
+        object::Architecture::Arm => Box::new(ArmV7),
+        object::Architecture::X86_64 => Box::new(X86::new(64)),
+        object::Architecture::I386 => Box::new(X86::new(32)),
+        machine => bail!("unsupported architecture {:?}", machine),
+    })
+}
@@ -0,0 +1,493 @@
+//! 32 and 64-bit x86, decoded with iced-x86.
+
+use super::{Anchor, Flow, Insn, InstructionSet, Reg, RegSet, Value};
+use crate::error::{trace_bail, TraceResult};
+use crate::ops::Cond;
+use crate::relocs::RelocatedMemory;
+use iced_x86::{
+    ConditionCode, Decoder, DecoderOptions, FlowControl, Instruction, InstructionInfoFactory,
+    Mnemonic, OpAccess, OpKind, Register,
+};
+use std::cell::RefCell;
+
+/// The longest x86 instruction
+const MAX_INSN_LEN: usize = 15;
+/// A call to the instruction right after it
+const CALL_TO_NEXT: [u8; 5] = [0xe8, 0, 0, 0, 0];
+
+/// Registers that are not preserved across calls in the System V ABIs:
+/// `rax`, `rcx`, `rdx`, `rsi`, `rdi` and `r8` to `r11` on x86_64, and only
+/// `eax`, `ecx` and `edx` on x86
+const CALLER_SAVED_64: [Reg; 9] = [0, 1, 2, 6, 7, 8, 9, 10, 11];
+const CALLER_SAVED_32: [Reg; 3] = [0, 1, 2];
+
+const REG_NAMES_64: [&str; 16] = [
+    "rax", "rcx", "rdx", "rbx", "rsp", "rbp", "rsi", "rdi", "r8", "r9", "r10", "r11", "r12", "r13",
+    "r14", "r15",
+];
+const REG_NAMES_32: [&str; 8] = ["eax", "ecx", "edx", "ebx", "esp", "ebp", "esi", "edi"];
+
+thread_local! {
+    static INFO_FACTORY: RefCell<InstructionInfoFactory> = RefCell::new(InstructionInfoFactory::new());
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct X86 {
+    /// 32 or 64
+    bitness: u32,
+}
+
+impl X86 {
+    pub fn new(bitness: u32) -> Self {
+        Self { bitness }
+    }
+
+    /// A displacement, which iced gives zero extended in 32-bit code
+    fn disp(&self, disp: u64) -> i64 {
+        match self.bitness {
+            32 => disp as u32 as i32 as i64,
+            _ => disp as i64,
+        }
+    }
+
+    /// The register set by the `__x86.get_pc_thunk` function at `addr`, which
+    /// 32-bit position independent code calls to find its own address
+    fn pc_thunk(&self, memory: &RelocatedMemory, addr: u64) -> Option<Reg> {
+        if self.bitness != 32 {
+            return None;
+        }
+        // mov <reg>, [esp]; ret
+        match *memory.read(addr, 4).ok()? {
+            [0x8b, modrm, 0x24, 0xc3] if modrm & 0xc7 == 0x04 => Some((modrm >> 3) & 0b111),
+            _ => None,
+        }
+    }
+
+    fn def(&self, ins: &Instruction) -> Option<(Reg, Value)> {
+        if ins.op0_kind() != OpKind::Register {
+            return None;
+        }
+        let dest = reg_index(ins.op0_register())?;
+        let value = match (ins.mnemonic(), ins.op1_kind()) {
+            (Mnemonic::Lea | Mnemonic::Mov, OpKind::Memory) if ins.is_ip_rel_memory_operand() => {
+                let addr = ins.ip_rel_memory_address();
+                match ins.mnemonic() {
+                    Mnemonic::Lea => Value::Const(addr),
+                    _ => Value::LoadFrom(addr),
+                }
+            }
+            (Mnemonic::Lea | Mnemonic::Mov, OpKind::Memory) => {
+                if ins.memory_index() != Register::None {
+                    return None;
+                }
+                let base = reg_index(ins.memory_base())?;
+                let offset = self.disp(ins.memory_displacement64());
+                match ins.mnemonic() {
+                    Mnemonic::Lea => Value::Add(base, offset),
+                    _ => Value::Load(base, offset),
+                }
+            }
+            (Mnemonic::Mov, OpKind::Register) => Value::Add(reg_index(ins.op1_register())?, 0),
+            (Mnemonic::Mov, kind) if is_immediate(kind) => Value::Const(ins.immediate(1)),
+            (Mnemonic::Add, kind) if is_immediate(kind) => {
+                Value::Add(dest, ins.immediate(1) as i64)
+            }
+            (Mnemonic::Sub, kind) if is_immediate(kind) => {
+                Value::Add(dest, (ins.immediate(1) as i64).wrapping_neg())
+            }
+            _ => return None,
+        };
+        Some((dest, value))
+    }
+
+    /// The register used as the base of a memory operand or added to by an
+    /// `ADD` or `SUB` immediate, and the offset added to it
+    fn base(&self, ins: &Instruction) -> Option<(Reg, i64)> {
+        match self.def(ins) {
+            Some((dest, Value::Add(src, offset))) if dest == src && offset != 0 => {
+                return Some((dest, offset))
+            }
+            _ => {}
+        }
+        let memory = (0..ins.op_count()).any(|op| ins.op_kind(op) == OpKind::Memory);
+        if !memory || ins.is_ip_rel_memory_operand() || ins.memory_index() != Register::None {
+            return None;
+        }
+        let base = reg_index(ins.memory_base())?;
+        Some((base, self.disp(ins.memory_displacement64())))
+    }
+
+    /// Where an indirect call or branch goes: a register or a pointer in memory
+    fn indirect_target(&self, ins: &Instruction) -> Option<Value> {
+        match ins.op0_kind() {
+            OpKind::Register => Some(Value::Add(reg_index(ins.op0_register())?, 0)),
+            OpKind::Memory if ins.is_ip_rel_memory_operand() => {
+                Some(Value::LoadFrom(ins.ip_rel_memory_address()))
+            }
+            OpKind::Memory if ins.memory_index() == Register::None => Some(Value::Load(
+                reg_index(ins.memory_base())?,
+                self.disp(ins.memory_displacement64()),
+            )),
+            _ => None,
+        }
+    }
+
+    fn writes(&self, ins: &Instruction) -> RegSet {
+        let mut writes = INFO_FACTORY.with(|factory| {
+            let mut factory = factory.borrow_mut();
+            factory
+                .info(ins)
+                .used_registers()
+                .iter()
+                .filter(|used| {
+                    matches!(
+                        used.access(),
+                        OpAccess::Write
+                            | OpAccess::CondWrite
+                            | OpAccess::ReadWrite
+                            | OpAccess::ReadCondWrite
+                    )
+                })
+                .filter_map(|used| reg_index(used.register()))
+                .collect::<RegSet>()
+        });
+        let call = match ins.flow_control() {
+            FlowControl::Call => !is_call_to_next(ins),
+            FlowControl::IndirectCall => true,
+            _ => false,
+        };
+        if call {
+            let clobbered: &[Reg] = match self.bitness {
+                32 => &CALLER_SAVED_32,
+                _ => &CALLER_SAVED_64,
+            };
+            for &reg in clobbered {
+                writes.insert(reg);
+            }
+        }
+        writes
+    }
+}
+
+impl InstructionSet for X86 {
+    fn name(&self) -> &'static str {
+        match self.bitness {
+            32 => "x86",
+            _ => "x86_64",
+        }
+    }
+
+    fn decode(&self, memory: &RelocatedMemory, addr: u64) -> TraceResult<Insn> {
+        // Instructions near the end of a segment are shorter than the longest
+        let mut len = MAX_INSN_LEN;
+        let data = loop {
+            match memory.read(addr, len) {
+                Ok(data) => break data,
+                Err(err) if len == 1 => return Err(err.into()),
+                Err(_) => len -= 1,
+            }
+        };
+        let ins = Decoder::with_ip(self.bitness, data, addr, DecoderOptions::NONE).decode();
+        if ins.is_invalid() {
+            trace_bail!(DecodeError, "invalid instruction at {:#x}", addr);
+        }
+
+        let mut def = self.def(&ins);
+        let mut anchor = match def {
+            Some((_, Value::Const(addr) | Value::LoadFrom(addr)))
+                if ins.is_ip_rel_memory_operand() =>
+            {
+                Some(Anchor::Complete(addr))
+            }
+            _ => None,
+        };
+        let mut writes = self.writes(&ins);
+        if ins.mnemonic() == Mnemonic::Pop
+            && ins.op0_kind() == OpKind::Register
+            && after_call_to_next(memory, addr)
+        {
+            if let Some(reg) = reg_index(ins.op0_register()) {
+                def = Some((reg, Value::Const(addr)));
+                anchor = Some(Anchor::Partial);
+            }
+        }
+        let flow = match ins.flow_control() {
+            // Only pushes the address of the next instruction
+            FlowControl::Call if is_call_to_next(&ins) => Flow::Next,
+            FlowControl::Call => {
+                let to = ins.near_branch_target();
+                if let Some(reg) = self.pc_thunk(memory, to) {
+                    def = Some((reg, Value::Const(ins.next_ip())));
+                    anchor = Some(Anchor::Partial);
+                    writes.insert(reg);
+                }
+                Flow::Call(to)
+            }
+            FlowControl::UnconditionalBranch => Flow::Branch(ins.near_branch_target()),
+            FlowControl::ConditionalBranch => match cond(ins.condition_code()) {
+                Some(cond) => Flow::CondBranch(cond, ins.near_branch_target()),
+                None => Flow::Next,
+            },
+            FlowControl::IndirectCall => Flow::Indirect {
+                target: self.indirect_target(&ins),
+                call: true,
+            },
+            FlowControl::IndirectBranch => Flow::Indirect {
+                target: self.indirect_target(&ins),
+                call: false,
+            },
+            FlowControl::Return => Flow::Return,
+            _ => Flow::Next,
+        };
+
+        Ok(Insn {
+            addr,
+            len: ins.len() as u8,
+            flow,
+            def,
+            anchor,
+            writes,
+            base: self.base(&ins),
+            prologue: is_prologue(&ins),
+        })
+    }
+
+    fn reg_name(&self, reg: Reg) -> String {
+        let names: &[&str] = match self.bitness {
+            32 => &REG_NAMES_32,
+            _ => &REG_NAMES_64,
+        };
+        names.get(reg as usize).copied().unwrap_or("?").to_owned()
+    }
+
+    fn insn_len(&self) -> (u64, u64) {
+        (1, MAX_INSN_LEN as u64)
+    }
+
+    fn is_aligned(&self, _addr: u64) -> bool {
+        true
+    }
+}
+
+/// The number of a general purpose register, the same for every width of it.
+/// Returns `None` for the instruction pointer and every other register.
+fn reg_index(reg: Register) -> Option<Reg> {
+    let full = reg.full_register();
+    if full.is_gpr64() {
+        Some(full.number() as Reg)
+    } else {
+        None
+    }
+}
+
+/// Whether the instruction at `addr` is reached by a call to the next
+/// instruction, which 32-bit position independent code uses to push its own
+/// address for a `pop` to take
+fn after_call_to_next(memory: &RelocatedMemory, addr: u64) -> bool {
+    let len = CALL_TO_NEXT.len() as u64;
+    addr >= len
+        && memory
+            .read(addr - len, CALL_TO_NEXT.len())
+            .is_ok_and(|data| *data == CALL_TO_NEXT)
+}
+
+fn is_call_to_next(ins: &Instruction) -> bool {
+    ins.near_branch_target() == ins.next_ip()
+}
+
+fn is_immediate(kind: OpKind) -> bool {
+    matches!(
+        kind,
+        OpKind::Immediate8
+            | OpKind::Immediate16
+            | OpKind::Immediate32
+            | OpKind::Immediate64
+            | OpKind::Immediate8to16
+            | OpKind::Immediate8to32
+            | OpKind::Immediate8to64
+            | OpKind::Immediate32to64
+    )
+}
+
+/// Whether `ins` looks like the first instruction of a function: a branch
+/// target marker, saving a register or making room on the stack.
+fn is_prologue(ins: &Instruction) -> bool {
+    let is_sp = |reg: Register| matches!(reg, Register::RSP | Register::ESP);
+    match ins.mnemonic() {
+        Mnemonic::Endbr64 | Mnemonic::Endbr32 => true,
+        Mnemonic::Push => ins.op0_kind() == OpKind::Register,
+        Mnemonic::Sub => ins.op0_kind() == OpKind::Register && is_sp(ins.op0_register()),
+        _ => false,
+    }
+}
+
+/// The condition a `Jcc` is taken on, with the unsigned comparisons under
+/// their ARM names. The parity conditions have no equivalent.
+fn cond(cc: ConditionCode) -> Option<Cond> {
+    Some(match cc {
+        ConditionCode::e => Cond::Eq,
+        ConditionCode::ne => Cond::Ne,
+        ConditionCode::ae => Cond::Cs,
+        ConditionCode::b => Cond::Cc,
+        ConditionCode::s => Cond::Mi,
+        ConditionCode::ns => Cond::Pl,
+        ConditionCode::o => Cond::Vs,
+        ConditionCode::no => Cond::Vc,
+        ConditionCode::a => Cond::Hi,
+        ConditionCode::be => Cond::Ls,
+        ConditionCode::ge => Cond::Ge,
+        ConditionCode::l => Cond::Lt,
+        ConditionCode::g => Cond::Gt,
+        ConditionCode::le => Cond::Le,
+        _ => return None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::FailureKind;
+    use crate::memory::AddressSpace;
+    use crate::tracer::tests::trace;
+    use std::collections::HashMap;
+
+    /// 32-bit position independent code finding its GOT at 0x2ff4 with a
+    /// call to the next instruction, then with `__x86.get_pc_thunk.bx`
+    const PIC_32: &[u8] = &[
+        0x53, // 1000: push ebx
+        0xe8, 0x00, 0x00, 0x00, 0x00, // 1001: call 1006
+        0x5b, // 1006: pop ebx
+        0x81, 0xc3, 0xee, 0x1f, 0x00, 0x00, // 1007: add ebx, 0x1fee
+        0x8d, 0x83, 0x0c, 0x00, 0x00, 0x00, // 100d: lea eax, [ebx+0xc]
+        0x8b, 0x8b, 0xfc, 0xff, 0xff, 0xff, // 1013: mov ecx, [ebx-0x4]
+        0xff, 0xd1, // 1019: call ecx
+        0x5b, // 101b: pop ebx
+        0xc3, // 101c: ret
+        0x53, // 101d: push ebx
+        0xe8, 0x0f, 0x00, 0x00, 0x00, // 101e: call 1032
+        0x81, 0xc3, 0xd1, 0x1f, 0x00, 0x00, // 1023: add ebx, 0x1fd1
+        0x8d, 0x83, 0x0c, 0x00, 0x00, 0x00, // 1029: lea eax, [ebx+0xc]
+        0x5b, // 102f: pop ebx
+        0xc3, // 1030: ret
+        0xc3, // 1031: ret
+        0x8b, 0x1c, 0x24, // 1032: mov ebx, [esp]
+        0xc3, // 1035: ret
+    ];
+
+    fn decode(bitness: u32, code: &[u8], addr: u64) -> Insn {
+        let memory = RelocatedMemory::from_raw(
+            AddressSpace::from_raw(&[(0x1000, code)]),
+            bitness as u64 / 8,
+            HashMap::new(),
+            HashMap::new(),
+        );
+        X86::new(bitness).decode(&memory, addr).unwrap()
+    }
+
+    #[test]
+    fn pops_the_address_pushed_by_a_call_to_next() {
+        let call = decode(32, PIC_32, 0x1001);
+        assert_eq!(call.flow, Flow::Next);
+        assert!(!call.writes.contains(0));
+        let pop = decode(32, PIC_32, 0x1006);
+        assert_eq!(pop.def, Some((3, Value::Const(0x1006))));
+        assert_eq!(pop.anchor, Some(Anchor::Partial));
+        // Not after a call to next
+        assert_eq!(decode(32, PIC_32, 0x101b).def, None);
+    }
+
+    #[test]
+    fn follows_adds_and_loads_from_a_register() {
+        let add = decode(32, PIC_32, 0x1007);
+        assert_eq!(add.def, Some((3, Value::Add(3, 0x1fee))));
+        assert_eq!(add.base, Some((3, 0x1fee)));
+        assert_eq!(
+            decode(32, PIC_32, 0x100d).def,
+            Some((0, Value::Add(3, 0xc)))
+        );
+        let load = decode(32, PIC_32, 0x1013);
+        assert_eq!(load.def, Some((1, Value::Load(3, -4))));
+        assert_eq!(load.base, Some((3, -4)));
+        let call = decode(32, PIC_32, 0x1019);
+        assert_eq!(
+            call.flow,
+            Flow::Indirect {
+                target: Some(Value::Add(1, 0)),
+                call: true
+            }
+        );
+        assert!(call.writes.contains(0));
+    }
+
+    #[test]
+    fn calls_to_the_pc_thunk_set_its_register() {
+        let call = decode(32, PIC_32, 0x101e);
+        assert_eq!(call.flow, Flow::Call(0x1032));
+        assert_eq!(call.def, Some((3, Value::Const(0x1023))));
+        assert_eq!(call.anchor, Some(Anchor::Partial));
+        assert!(call.writes.contains(3));
+    }
+
+    #[test]
+    fn anchors_rip_relative_operands() {
+        let code = [
+            0x48, 0x8d, 0x05, 0xf9, 0x0f, 0x00, 0x00, // 1000: lea rax, [rip+0xff9]
+            0x48, 0x8b, 0x0d, 0xf2, 0x0f, 0x00, 0x00, // 1007: mov rcx, [rip+0xff2]
+            0xff, 0x15, 0xec, 0x0f, 0x00, 0x00, // 100e: call [rip+0xfec]
+        ];
+        let lea = decode(64, &code, 0x1000);
+        assert_eq!(lea.def, Some((0, Value::Const(0x2000))));
+        assert_eq!(lea.anchor, Some(Anchor::Complete(0x2000)));
+        let mov = decode(64, &code, 0x1007);
+        assert_eq!(mov.def, Some((1, Value::LoadFrom(0x2000))));
+        assert_eq!(mov.anchor, Some(Anchor::Complete(0x2000)));
+        assert_eq!(
+            decode(64, &code, 0x100e).flow,
+            Flow::Indirect {
+                target: Some(Value::LoadFrom(0x2000)),
+                call: true
+            }
+        );
+    }
+
+    fn trace_pic_32(start: &str, ops: &str) -> Result<u64, FailureKind> {
+        // The GOT entry at 0x2ff0 points to the `ret` at 0x1031
+        let mut got = [0; 0x20];
+        got[..4].copy_from_slice(&0x1031u32.to_le_bytes());
+        let functions = [
+            0x1000..0x101d,
+            0x101d..0x1031,
+            0x1031..0x1032,
+            0x1032..0x1036,
+        ];
+        trace(
+            Box::new(X86::new(32)),
+            4,
+            &[(0x1000, PIC_32), (0x2ff0, &got)],
+            &functions,
+            start,
+            ops,
+        )
+    }
+
+    #[test]
+    fn traces_addresses_found_with_a_call_to_next() {
+        assert_eq!(trace_pic_32("addr:0x1000", "P0"), Ok(0x2ff4));
+        assert_eq!(trace_pic_32("addr:0x1000", "P0.1"), Ok(0x3000));
+        assert_eq!(trace_pic_32("addr:0x1000", "P0.2"), Ok(0x2ff0));
+        assert_eq!(trace_pic_32("addr:0x1000", "P0.2D"), Ok(0x1031));
+        assert_eq!(trace_pic_32("addr:0x1000", "R0"), Ok(0x1031));
+        // The call to next is not a call to a function
+        assert_eq!(
+            trace_pic_32("addr:0x1000", "L0"),
+            Err(FailureKind::FunctionOverrun)
+        );
+    }
+
+    #[test]
+    fn traces_addresses_found_with_the_pc_thunk() {
+        assert_eq!(trace_pic_32("addr:0x101d", "P0"), Ok(0x2ff4));
+        assert_eq!(trace_pic_32("addr:0x101d", "P0.1"), Ok(0x3000));
+        assert_eq!(trace_pic_32("addr:0x101d", "L0"), Ok(0x1032));
+    }
+}
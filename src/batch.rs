@@ -0,0 +1,214 @@
+//! Applying the same xref data to many builds of an application in one run.
+
+use crate::{Output, XRefData};
+use color_eyre::eyre::{bail, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
+
+/// The name of the summary file written next to the output of each build,
+/// without its `.json` extension
+pub const SUMMARY_NAME: &str = "summary";
+
+/// The builds to apply xref data to, read from a JSON file like
+/// `{"builds": [{"name": "1.0", "shared_object": "...", "metadata": "..."}]}`
+#[derive(Deserialize, Debug)]
+pub struct Manifest {
+    pub builds: Vec<Build>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct Build {
+    /// The name of the build, which its output file is named after
+    pub name: String,
+    /// The path to the build's il2cpp shared object file (libil2cpp.so)
+    pub shared_object: PathBuf,
+    /// The path to the build's il2cpp metadata file (global-metadata.dat)
+    pub metadata: PathBuf,
+}
+
+impl Manifest {
+    /// Checks that every build has a distinct name that can be used as a
+    /// file name next to the summary.
+    pub fn validate(&self) -> Result<()> {
+        let mut names = HashSet::new();
+        for build in &self.builds {
+            let name = build.name.as_str();
+            if name.is_empty() || name == "." || name == ".." || name.contains(['/', '\\']) {
+                bail!("build name '{}' cannot be used as a file name", name);
+            }
+            if name == SUMMARY_NAME {
+                bail!("build name '{}' is used for the summary", name);
+            }
+            if !names.insert(name) {
+                bail!("build name '{}' is used more than once", name);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Which symbols were found in which build
+#[derive(Serialize, Debug)]
+pub struct Summary<'x> {
+    pub builds: Vec<BuildSummary>,
+    /// Every symbol in the xref data, in the order they first appear
+    pub symbols: Vec<SymbolSummary<'x>>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct BuildSummary {
+    pub name: String,
+    /// The number of symbols found
+    pub found: usize,
+    /// Why the build could not be traced at all
+    pub error: Option<String>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct SymbolSummary<'x> {
+    pub symbol: &'x str,
+    /// The address of the symbol in each build, in the order of
+    /// [`Summary::builds`], or `None` if it was not found
+    pub offsets: Vec<Option<u64>>,
+}
+
+impl<'x> Summary<'x> {
+    pub fn new(xref_data: &'x XRefData) -> Self {
+        Self {
+            builds: Vec::new(),
+            symbols: xref_data
+                .symbols()
+                .into_iter()
+                .map(|(symbol, _)| SymbolSummary {
+                    symbol,
+                    offsets: Vec::new(),
+                })
+                .collect(),
+        }
+    }
+
+    /// Adds a column for the build `name`, from its output or the error that
+    /// stopped it from being traced.
+    pub fn add(&mut self, name: &str, output: &Result<Output>) {
+        let (offsets, error) = match output {
+            Ok(output) => (
+                output
+                    .symbols
+                    .iter()
+                    .map(|symbol| (symbol.symbol, symbol.offset))
+                    .collect(),
+                None,
+            ),
+            Err(err) => (HashMap::new(), Some(format!("{:#}", err))),
+        };
+        for symbol in &mut self.symbols {
+            symbol.offsets.push(offsets.get(symbol.symbol).copied());
+        }
+        self.builds.push(BuildSummary {
+            name: name.to_owned(),
+            found: offsets.len(),
+            error,
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{OutputSymbol, SymbolTrace, Validation};
+    use color_eyre::eyre::eyre;
+
+    fn manifest(names: &[&str]) -> Manifest {
+        Manifest {
+            builds: names
+                .iter()
+                .map(|&name| Build {
+                    name: name.to_owned(),
+                    shared_object: PathBuf::from("libil2cpp.so"),
+                    metadata: PathBuf::from("global-metadata.dat"),
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn validates_build_names() {
+        assert!(manifest(&[]).validate().is_ok());
+        assert!(manifest(&["1.0", "1.1", "summary.old"]).validate().is_ok());
+        for names in [
+            &[""][..],
+            &["."],
+            &[".."],
+            &["1.0/arm64"],
+            &["1.0\\arm64"],
+            &[SUMMARY_NAME],
+            &["1.0", "1.1", "1.0"],
+        ] {
+            assert!(manifest(names).validate().is_err(), "{:?}", names);
+        }
+    }
+
+    #[test]
+    fn keeps_columns_aligned_for_failed_builds() {
+        let xref_data = XRefData {
+            traces: ["a", "b", "a"]
+                .into_iter()
+                .map(|symbol| SymbolTrace {
+                    symbol: symbol.to_owned(),
+                    start: "entry".to_owned(),
+                    trace: String::new(),
+                })
+                .collect(),
+        };
+        let found = |symbol, offset| OutputSymbol {
+            symbol,
+            offset,
+            confidence: 1.0,
+            validation: Validation::default(),
+            traces: 1,
+        };
+        let mut summary = Summary::new(&xref_data);
+        summary.add(
+            "1.0",
+            &Ok(Output {
+                symbols: vec![found("b", 0x20)],
+                ..Output::default()
+            }),
+        );
+        summary.add("1.1", &Err(eyre!("not an ELF file")));
+        summary.add(
+            "1.2",
+            &Ok(Output {
+                symbols: vec![found("a", 0x10), found("b", 0x30)],
+                ..Output::default()
+            }),
+        );
+
+        let builds: Vec<_> = summary
+            .builds
+            .iter()
+            .map(|build| (build.name.as_str(), build.found, build.error.as_deref()))
+            .collect();
+        assert_eq!(
+            builds,
+            [
+                ("1.0", 1, None),
+                ("1.1", 0, Some("not an ELF file")),
+                ("1.2", 2, None)
+            ]
+        );
+        let symbols: Vec<_> = summary
+            .symbols
+            .iter()
+            .map(|symbol| (symbol.symbol, symbol.offsets.as_slice()))
+            .collect();
+        assert_eq!(
+            symbols,
+            [
+                ("a", &[None, None, Some(0x10)][..]),
+                ("b", &[Some(0x20), None, Some(0x30)][..])
+            ]
+        );
+    }
+}
@@ -0,0 +1,262 @@
+//! Ordering of symbols whose traces start from other symbols in the same
+//! xref data.
+
+use crate::error::{trace_bail, TraceResult};
+use crate::start::Start;
+use crate::XRefData;
+use std::collections::{HashMap, HashSet};
+
+/// The symbols of an xref file, ordered so that every symbol comes after the
+/// symbols its `xref:` starts depend on.
+#[derive(Debug)]
+pub struct DependencyOrder<'x> {
+    /// Each symbol with the indices of its traces, as in [`XRefData::symbols`]
+    pub symbols: Vec<(&'x str, Vec<usize>)>,
+    /// Positions in `symbols` grouped so that each symbol only depends on
+    /// symbols before it in an earlier wave. Symbols of the same wave can be
+    /// traced at the same time.
+    pub waves: Vec<Vec<usize>>,
+    /// Symbols that are part of a dependency cycle
+    pub cyclic: HashSet<&'x str>,
+}
+
+impl<'x> DependencyOrder<'x> {
+    /// Orders the symbols of `xref_data` by their dependencies. Symbols that
+    /// do not depend on each other keep the order they first appear in.
+    pub fn new(xref_data: &'x XRefData) -> Self {
+        let symbols = xref_data.symbols();
+        let positions: HashMap<&str, usize> = symbols
+            .iter()
+            .enumerate()
+            .map(|(pos, &(symbol, _))| (symbol, pos))
+            .collect();
+        // Dependencies on symbols that are not in the file are reported when
+        // the trace runs
+        let deps: Vec<Vec<usize>> = symbols
+            .iter()
+            .map(|(_, indices)| {
+                indices
+                    .iter()
+                    .filter_map(|&idx| Start::dependency(&xref_data.traces[idx].start))
+                    .filter_map(|dep| positions.get(dep).copied())
+                    .collect()
+            })
+            .collect();
+
+        let mut visitor = Visitor {
+            deps: &deps,
+            states: vec![VisitState::New; symbols.len()],
+            stack: Vec::new(),
+            order: Vec::with_capacity(symbols.len()),
+            cyclic: vec![false; symbols.len()],
+        };
+        for node in 0..symbols.len() {
+            visitor.visit(node);
+        }
+
+        let cyclic = symbols
+            .iter()
+            .zip(&visitor.cyclic)
+            .filter(|&(_, &cyclic)| cyclic)
+            .map(|(&(symbol, _), _)| symbol)
+            .collect();
+        let mut rank = vec![0; symbols.len()];
+        for (pos, &node) in visitor.order.iter().enumerate() {
+            rank[node] = pos;
+        }
+        // Dependencies on symbols later in the order are part of a cycle and
+        // lead back through symbols with deeper waves, so they are never
+        // resolved before the symbol is traced
+        let mut depth = vec![0; symbols.len()];
+        let mut waves: Vec<Vec<usize>> = Vec::new();
+        for &node in &visitor.order {
+            depth[node] = deps[node]
+                .iter()
+                .filter(|&&dep| rank[dep] < rank[node])
+                .map(|&dep| depth[dep] + 1)
+                .max()
+                .unwrap_or(0);
+            if waves.len() <= depth[node] {
+                waves.resize_with(depth[node] + 1, Vec::new);
+            }
+            waves[depth[node]].push(rank[node]);
+        }
+
+        let mut ranked: Vec<_> = rank.into_iter().zip(symbols).collect();
+        ranked.sort_unstable_by_key(|&(rank, _)| rank);
+
+        Self {
+            symbols: ranked.into_iter().map(|(_, symbol)| symbol).collect(),
+            waves,
+            cyclic,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum VisitState {
+    New,
+    Visiting,
+    Done,
+}
+
+/// Depth-first post-order walk of the dependency graph
+struct Visitor<'a> {
+    deps: &'a [Vec<usize>],
+    states: Vec<VisitState>,
+    /// The nodes being visited, innermost last
+    stack: Vec<usize>,
+    order: Vec<usize>,
+    cyclic: Vec<bool>,
+}
+
+impl<'a> Visitor<'a> {
+    fn visit(&mut self, node: usize) {
+        match self.states[node] {
+            VisitState::Done => return,
+            VisitState::Visiting => {
+                // Every node on the stack from `node` up depends on itself
+                if let Some(from) = self.stack.iter().rposition(|&other| other == node) {
+                    for &other in &self.stack[from..] {
+                        self.cyclic[other] = true;
+                    }
+                }
+                return;
+            }
+            VisitState::New => {}
+        }
+
+        self.states[node] = VisitState::Visiting;
+        self.stack.push(node);
+        let deps = self.deps;
+        for &dep in &deps[node] {
+            self.visit(dep);
+        }
+        self.stack.pop();
+        self.states[node] = VisitState::Done;
+        self.order.push(node);
+    }
+}
+
+/// The addresses of the symbols traced so far, which `xref:` starts refer to
+#[derive(Debug)]
+pub struct ResolvedSymbols<'x> {
+    addrs: HashMap<&'x str, u64>,
+    known: HashSet<&'x str>,
+    cyclic: HashSet<&'x str>,
+}
+
+impl<'x> ResolvedSymbols<'x> {
+    pub fn new(order: &DependencyOrder<'x>) -> Self {
+        Self {
+            addrs: HashMap::new(),
+            known: order.symbols.iter().map(|&(symbol, _)| symbol).collect(),
+            cyclic: order.cyclic.clone(),
+        }
+    }
+
+    pub fn insert(&mut self, symbol: &'x str, addr: u64) {
+        self.addrs.insert(symbol, addr);
+    }
+
+    /// The address of `symbol`, which must already have been traced
+    pub fn get(&self, symbol: &str) -> TraceResult<u64> {
+        if let Some(&addr) = self.addrs.get(symbol) {
+            return Ok(addr);
+        }
+        if !self.known.contains(symbol) {
+            trace_bail!(
+                UnresolvedDependency,
+                "symbol '{}' is not traced by the xref data",
+                symbol
+            );
+        }
+        if self.cyclic.contains(symbol) {
+            trace_bail!(
+                DependencyCycle,
+                "symbol '{}' is part of a cycle of xref starts",
+                symbol
+            );
+        }
+        trace_bail!(
+            UnresolvedDependency,
+            "symbol '{}' was not resolved because its traces failed or disagree",
+            symbol
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::SymbolTrace;
+
+    fn xref_data(traces: &[(&str, &str)]) -> XRefData {
+        XRefData {
+            traces: traces
+                .iter()
+                .map(|&(symbol, start)| SymbolTrace {
+                    symbol: symbol.to_owned(),
+                    start: start.to_owned(),
+                    trace: "L0".to_owned(),
+                })
+                .collect(),
+        }
+    }
+
+    fn names<'x>(order: &DependencyOrder<'x>, positions: &[usize]) -> Vec<&'x str> {
+        positions.iter().map(|&pos| order.symbols[pos].0).collect()
+    }
+
+    #[test]
+    fn orders_symbols_after_their_dependencies() {
+        let data = xref_data(&[
+            ("a", "xref:b"),
+            ("b", "addr:0x10"),
+            ("c", "xref:a"),
+            ("d", "il2cpp:Foo.Bar"),
+        ]);
+        let order = DependencyOrder::new(&data);
+        let all: Vec<usize> = (0..order.symbols.len()).collect();
+        assert_eq!(names(&order, &all), ["b", "a", "c", "d"]);
+        assert_eq!(order.symbols[1].1, [0]);
+        assert!(order.cyclic.is_empty());
+
+        let waves: Vec<_> = order.waves.iter().map(|w| names(&order, w)).collect();
+        assert_eq!(waves, [vec!["b", "d"], vec!["a"], vec!["c"]]);
+    }
+
+    #[test]
+    fn finds_cycles() {
+        let data = xref_data(&[
+            ("a", "xref:b"),
+            ("b", "xref:a"),
+            ("c", "xref:c"),
+            ("d", "xref:a"),
+            ("e", "xref:missing"),
+        ]);
+        let order = DependencyOrder::new(&data);
+        let mut cyclic: Vec<_> = order.cyclic.iter().copied().collect();
+        cyclic.sort_unstable();
+        assert_eq!(cyclic, ["a", "b", "c"]);
+
+        // A symbol in a cycle is traced before the symbol it depends on
+        // further along the cycle is resolved
+        let mut resolved = ResolvedSymbols::new(&order);
+        let err = resolved.get("a").unwrap_err();
+        assert_eq!(err.kind, crate::FailureKind::DependencyCycle);
+        resolved.insert("a", 0x10);
+        assert_eq!(resolved.get("a").unwrap(), 0x10);
+        let err = resolved.get("missing").unwrap_err();
+        assert_eq!(err.kind, crate::FailureKind::UnresolvedDependency);
+
+        // Every symbol comes in a later wave than the symbols before it that
+        // it depends on
+        let wave_of = |symbol: &str| {
+            let pos = order.symbols.iter().position(|&(s, _)| s == symbol);
+            order.waves.iter().position(|w| w.contains(&pos.unwrap()))
+        };
+        assert!(wave_of("d") > wave_of("a"));
+        assert!(wave_of("a") != wave_of("b"));
+    }
+}
@@ -0,0 +1,96 @@
+use crate::memory::MemoryError;
+use crate::relocs::PointerError;
+use serde::Serialize;
+use std::fmt;
+
+/// Machine-readable category of a trace failure
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum FailureKind {
+    /// The trace start is not a well-formed start spec
+    InvalidStart,
+    /// The il2cpp root the trace starts at could not be found
+    MissingRoot,
+    /// The il2cpp root the trace starts at matches several methods
+    AmbiguousRoot,
+    /// The il2cpp method the trace starts at has no compiled code, and no
+    /// fallback was configured or available
+    NullMethodPointer,
+    /// The trace starts at a symbol that is not in the dynamic symbol table
+    MissingSymbol,
+    /// The section, table or table entry the trace starts at does not exist
+    MissingStart,
+    /// An instruction along the walk could not be decoded
+    DecodeError,
+    /// The walk left the mapped, file-backed part of the shared object
+    OutOfBounds,
+    /// An instruction did not have the operands the trace op expected
+    OperandMismatch,
+    /// The value of a register could not be determined statically
+    UnresolvedRegister,
+    /// No instruction using the register an `ADRP` wrote to could be found
+    UnpairedAdrp,
+    /// An op reached the end of the function it was scanning before finding
+    /// enough matching instructions
+    FunctionOverrun,
+    /// The trace starts from another symbol in the xref data that could not
+    /// be resolved
+    UnresolvedDependency,
+    /// The trace starts from a symbol that, through its own traces, depends
+    /// on the symbol being traced
+    DependencyCycle,
+}
+
+/// An error that stops a single trace
+#[derive(Debug, Clone)]
+pub struct TraceError {
+    pub kind: FailureKind,
+    pub message: String,
+}
+
+impl TraceError {
+    pub fn new(kind: FailureKind, message: impl Into<String>) -> Self {
+        Self {
+            kind,
+            message: message.into(),
+        }
+    }
+}
+
+impl fmt::Display for TraceError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for TraceError {}
+
+impl From<MemoryError> for TraceError {
+    fn from(err: MemoryError) -> Self {
+        Self::new(FailureKind::OutOfBounds, err.to_string())
+    }
+}
+
+impl From<PointerError> for TraceError {
+    fn from(err: PointerError) -> Self {
+        match err {
+            PointerError::Memory(err) => err.into(),
+            PointerError::UndefinedSymbol { .. } => {
+                Self::new(FailureKind::MissingSymbol, err.to_string())
+            }
+        }
+    }
+}
+
+pub type TraceResult<T> = std::result::Result<T, TraceError>;
+
+/// Returns early with a [`TraceError`] of the given [`FailureKind`].
+macro_rules! trace_bail {
+    ($kind:ident, $($arg:tt)*) => {
+        return Err($crate::error::TraceError::new(
+            $crate::error::FailureKind::$kind,
+            format!($($arg)*),
+        ))
+    };
+}
+pub(crate) use trace_bail;
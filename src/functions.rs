@@ -0,0 +1,184 @@
+use crate::arch::InstructionSet;
+use color_eyre::eyre::Result;
+use gimli::{BaseAddresses, CieOrFde, EhFrame, LittleEndian, UnwindSection};
+use il2cpp_binary::CodeRegistration;
+use object::{Object, ObjectSection};
+use std::ops::Range;
+
+/// The bounds of the functions in the shared object.
+///
+/// Functions with unwind info get their exact bounds from the FDEs in
+/// `.eh_frame`. Any other function is assumed to run from its entry point to
+/// the next known entry point, where entry points are the starts of FDEs, the
+/// functions in the ARM exception index table and the il2cpp method, generic
+/// method and invoker pointers.
+///
+/// Addresses are without the bits that select the instruction set, see
+/// [`InstructionSet::code_addr`].
+#[derive(Debug, Default)]
+pub struct FunctionMap {
+    /// Ranges covered by FDEs, sorted by start address
+    fdes: Vec<Range<u64>>,
+    /// Known function entry points, sorted and deduplicated
+    entries: Vec<u64>,
+}
+
+impl FunctionMap {
+    pub fn new<'data: 'file, 'file, O: Object<'data, 'file>>(
+        elf: &'file O,
+        code_registration: Option<&CodeRegistration>,
+        arch: &dyn InstructionSet,
+    ) -> Result<Self> {
+        let fdes = eh_frame_fdes(elf)?;
+        let mut entries = arm_exidx_entries(elf)?;
+        if let Some(code_registration) = code_registration {
+            for module in &code_registration.code_gen_modules {
+                entries.extend(&module.method_pointers);
+            }
+            entries.extend(&code_registration.generic_method_pointers);
+            entries.extend(&code_registration.invoker_pointers);
+        }
+        for entry in &mut entries {
+            *entry = arch.code_addr(*entry);
+        }
+        Ok(Self::from_parts(fdes, entries))
+    }
+
+    /// A map of the functions covered by `fdes` and starting at `entries`,
+    /// which are entry points along with the starts of the FDEs
+    pub fn from_parts(mut fdes: Vec<Range<u64>>, mut entries: Vec<u64>) -> Self {
+        fdes.sort_by_key(|range| range.start);
+        entries.extend(fdes.iter().map(|range| range.start));
+        entries.retain(|&entry| entry != 0);
+        entries.sort_unstable();
+        entries.dedup();
+        Self { fdes, entries }
+    }
+
+    /// The bounds of the function containing `addr`, if they are known
+    pub fn bounds(&self, addr: u64) -> Option<Range<u64>> {
+        let idx = self.fdes.partition_point(|range| range.start <= addr);
+        if let Some(range) = idx.checked_sub(1).map(|idx| &self.fdes[idx]) {
+            if range.contains(&addr) {
+                return Some(range.clone());
+            }
+        }
+
+        // The last entry point has nothing after it to end it
+        let idx = self.entries.partition_point(|&entry| entry <= addr);
+        if idx == 0 || idx == self.entries.len() {
+            return None;
+        }
+        Some(self.entries[idx - 1]..self.entries[idx])
+    }
+
+    /// Whether `addr` is the entry point of a known function
+    pub fn is_entry(&self, addr: u64) -> bool {
+        self.entries.binary_search(&addr).is_ok()
+    }
+}
+
+/// Collects the address ranges of every FDE in `.eh_frame`. FDEs that cannot
+/// be parsed are skipped.
+fn eh_frame_fdes<'data: 'file, 'file, O: Object<'data, 'file>>(
+    elf: &'file O,
+) -> Result<Vec<Range<u64>>> {
+    let section = match elf.section_by_name(".eh_frame") {
+        Some(section) => section,
+        None => return Ok(Vec::new()),
+    };
+    let mut bases = BaseAddresses::default().set_eh_frame(section.address());
+    if let Some(text) = elf.section_by_name(".text") {
+        bases = bases.set_text(text.address());
+    }
+    if let Some(got) = elf.section_by_name(".got") {
+        bases = bases.set_got(got.address());
+    }
+
+    let mut eh_frame = EhFrame::new(section.data()?, LittleEndian);
+    eh_frame.set_address_size(if elf.is_64() { 8 } else { 4 });
+    let mut fdes = Vec::new();
+    let mut entries = eh_frame.entries(&bases);
+    while let Ok(Some(entry)) = entries.next() {
+        if let CieOrFde::Fde(partial) = entry {
+            if let Ok(fde) =
+                partial.parse(|_, bases, offset| eh_frame.cie_from_offset(bases, offset))
+            {
+                let start = fde.initial_address();
+                fdes.push(start..start + fde.len());
+            }
+        }
+    }
+    Ok(fdes)
+}
+
+/// Collects the start of every function in `.ARM.exidx`, which 32-bit ARM
+/// shared objects usually have instead of `.eh_frame`.
+fn arm_exidx_entries<'data: 'file, 'file, O: Object<'data, 'file>>(
+    elf: &'file O,
+) -> Result<Vec<u64>> {
+    let section = match elf.section_by_name(".ARM.exidx") {
+        Some(section) => section,
+        None => return Ok(Vec::new()),
+    };
+    let mut entries = Vec::new();
+    for (idx, entry) in section.data()?.chunks_exact(8).enumerate() {
+        // The first word is a signed 31-bit offset from the entry itself
+        let word = u32::from_le_bytes([entry[0], entry[1], entry[2], entry[3]]);
+        let offset = ((word << 1) as i32 >> 1) as i64;
+        let addr = section.address() + idx as u64 * 8;
+        entries.push(addr.wrapping_add(offset as u64) & 0xffff_ffff);
+    }
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn function_map() -> FunctionMap {
+        // Two functions with FDEs, one without any unwind info at 0x1200 and
+        // one at 0x1400 that is only known by its entry point
+        FunctionMap::from_parts(
+            vec![0x1100..0x1180, 0x1000..0x1080],
+            vec![0x1400, 0x1200, 0x1000, 0],
+        )
+    }
+
+    #[test]
+    fn finds_fde_bounds() {
+        let functions = function_map();
+        assert_eq!(functions.bounds(0x1000), Some(0x1000..0x1080));
+        assert_eq!(functions.bounds(0x107c), Some(0x1000..0x1080));
+        assert_eq!(functions.bounds(0x1100), Some(0x1100..0x1180));
+    }
+
+    #[test]
+    fn falls_back_to_entry_points() {
+        let functions = function_map();
+        // Past the end of an FDE, up to the next entry point
+        assert_eq!(functions.bounds(0x1080), Some(0x1000..0x1100));
+        assert_eq!(functions.bounds(0x1180), Some(0x1100..0x1200));
+        assert_eq!(functions.bounds(0x1300), Some(0x1200..0x1400));
+    }
+
+    #[test]
+    fn has_no_bounds_outside_of_entry_points() {
+        let functions = function_map();
+        assert_eq!(functions.bounds(0xffc), None);
+        // The last entry point has no known end
+        assert_eq!(functions.bounds(0x1400), None);
+        assert_eq!(functions.bounds(0x2000), None);
+        assert_eq!(FunctionMap::default().bounds(0x1000), None);
+    }
+
+    #[test]
+    fn knows_entry_points() {
+        let functions = function_map();
+        for entry in [0x1000, 0x1100, 0x1200, 0x1400] {
+            assert!(functions.is_entry(entry));
+        }
+        assert!(!functions.is_entry(0));
+        assert!(!functions.is_entry(0x1080));
+    }
+}
@@ -0,0 +1,36 @@
+//! Input files, memory-mapped so that parsing borrows from the mapping
+//! instead of a copy read into memory.
+
+use color_eyre::eyre::{Result, WrapErr};
+use memmap2::Mmap;
+use std::fs::File;
+use std::ops::Deref;
+use std::path::Path;
+
+/// A memory-mapped input file, which derefs to its contents and can be given
+/// to [`XRefApplyBuilder`](crate::XRefApplyBuilder) like any other slice.
+#[derive(Debug)]
+pub struct InputFile {
+    map: Mmap,
+}
+
+impl InputFile {
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let file =
+            File::open(path).wrap_err_with(|| format!("could not open {}", path.display()))?;
+        // SAFETY: the inputs are only read, and are not expected to change
+        // while they are being traced
+        let map = unsafe { Mmap::map(&file) }
+            .wrap_err_with(|| format!("could not map {}", path.display()))?;
+        Ok(Self { map })
+    }
+}
+
+impl Deref for InputFile {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.map
+    }
+}
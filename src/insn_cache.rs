@@ -0,0 +1,50 @@
+//! Decoded instructions shared by every trace over a shared object.
+//!
+//! Traces from the same roots walk the same instructions, and looking for the
+//! instructions that set a register decodes whole stretches of a function
+//! again. Decoding each address once keeps thousands of traces from being
+//! dominated by the decoder.
+
+use crate::arch::{Insn, InstructionSet};
+use crate::error::TraceResult;
+use crate::relocs::RelocatedMemory;
+use std::collections::HashMap;
+use std::sync::RwLock;
+
+/// How many separately locked maps the cache is split into, so traces running
+/// at the same time rarely wait on each other
+const SHARDS: usize = 64;
+
+/// Every instruction decoded so far, including addresses that failed to
+/// decode.
+#[derive(Debug)]
+pub struct InsnCache {
+    shards: Vec<RwLock<HashMap<u64, TraceResult<Insn>>>>,
+}
+
+impl Default for InsnCache {
+    fn default() -> Self {
+        Self {
+            shards: (0..SHARDS).map(|_| RwLock::default()).collect(),
+        }
+    }
+}
+
+impl InsnCache {
+    /// Decodes the instruction at `addr`, or returns it if it was decoded
+    /// before.
+    pub fn decode(
+        &self,
+        arch: &dyn InstructionSet,
+        memory: &RelocatedMemory,
+        addr: u64,
+    ) -> TraceResult<Insn> {
+        let shard = &self.shards[(addr as usize >> 1) % SHARDS];
+        if let Some(ins) = shard.read().unwrap().get(&addr) {
+            return ins.clone();
+        }
+        let ins = arch.decode(memory, addr);
+        shard.write().unwrap().insert(addr, ins.clone());
+        ins
+    }
+}
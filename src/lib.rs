@@ -0,0 +1,332 @@
+//! Find symbol addresses in Unity IL2CPP AArch64, ARMv7, x86_64 and x86
+//! applications using xref traces generated by
+//! [xref_gen](https://github.com/StackDoubleFlow/xref_gen).
+//!
+//! ```no_run
+//! # fn main() -> color_eyre::Result<()> {
+//! use xref_apply::{InputFile, XRefApply, XRefData};
+//!
+//! // Any byte slice works too, such as the contents read by `std::fs::read`
+//! let elf_data = InputFile::open("libil2cpp.so")?;
+//! let metadata_data = InputFile::open("global-metadata.dat")?;
+//! let xref_data: XRefData = serde_json::from_str(&std::fs::read_to_string("xref_gen.json")?)?;
+//!
+//! let xref_apply = XRefApply::builder()
+//!     .shared_object(&elf_data)
+//!     .metadata(&metadata_data)
+//!     .build()?;
+//! let output = xref_apply.trace(&xref_data)?;
+//! # Ok(())
+//! # }
+//! ```
+
+pub mod arch;
+pub mod batch;
+pub mod deps;
+pub mod error;
+pub mod functions;
+pub mod input;
+pub mod insn_cache;
+pub mod memory;
+pub mod ops;
+pub mod relocs;
+pub mod roots;
+pub mod shared_object;
+pub mod start;
+pub mod tracer;
+pub mod validate;
+
+pub use error::{FailureKind, TraceError};
+pub use input::InputFile;
+pub use ops::{parse_trace, SyntaxError, TraceOp};
+pub use roots::{
+    find_roots, MethodRef, NullMethod, NullMethodFallback, Params, Root, Roots, UnresolvedRoot,
+};
+pub use shared_object::SharedObject;
+pub use start::{Start, StartPoints};
+pub use tracer::XRefTracer;
+pub use validate::Validation;
+
+use color_eyre::eyre::{bail, ContextCompat, Result};
+use il2cpp_binary::{CodeRegistration, Elf, MetadataRegistration};
+use il2cpp_metadata_raw::Metadata;
+use object::read::elf::ElfFile32;
+use object::{Architecture, Endianness, FileKind, Object};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+
+#[derive(Deserialize, Debug)]
+pub struct SymbolTrace {
+    pub symbol: String,
+    pub start: String,
+    pub trace: String,
+}
+
+/// The traces created by xref_gen. A symbol may have several traces, which
+/// are alternative ways of finding it.
+#[derive(Deserialize, Debug)]
+pub struct XRefData {
+    pub traces: Vec<SymbolTrace>,
+}
+
+impl XRefData {
+    /// The indices of the traces for each symbol, in the order each symbol
+    /// first appears.
+    pub fn symbols(&self) -> Vec<(&str, Vec<usize>)> {
+        let mut symbols: Vec<(&str, Vec<usize>)> = Vec::new();
+        let mut positions: HashMap<&str, usize> = HashMap::new();
+        for (idx, trace) in self.traces.iter().enumerate() {
+            let symbol = trace.symbol.as_str();
+            match positions.get(symbol) {
+                Some(&pos) => symbols[pos].1.push(idx),
+                None => {
+                    positions.insert(symbol, symbols.len());
+                    symbols.push((symbol, vec![idx]));
+                }
+            }
+        }
+        symbols
+    }
+
+    /// Parses the trace string of every trace, failing with every syntax
+    /// error in the file if any of them are malformed.
+    pub fn parse_ops(&self) -> Result<ParsedOps<'_>> {
+        let mut ops = Vec::with_capacity(self.traces.len());
+        let mut errors = Vec::new();
+        for trace in &self.traces {
+            match parse_trace(&trace.trace) {
+                Ok(trace_ops) => ops.push(trace_ops),
+                Err(err) => errors.push(format!(
+                    "symbol '{}' has invalid trace '{}': {}",
+                    trace.symbol, trace.trace, err
+                )),
+            }
+        }
+        if !errors.is_empty() {
+            bail!("invalid traces in xref data:\n{}", errors.join("\n"));
+        }
+        Ok(ParsedOps {
+            xref_data: self,
+            ops,
+        })
+    }
+}
+
+/// The ops of every trace in some xref data, which can be traced over
+/// several builds after parsing them once
+#[derive(Debug)]
+pub struct ParsedOps<'x> {
+    xref_data: &'x XRefData,
+    ops: Vec<Vec<TraceOp>>,
+}
+
+impl<'x> ParsedOps<'x> {
+    /// The xref data the ops were parsed from
+    pub fn xref_data(&self) -> &'x XRefData {
+        self.xref_data
+    }
+
+    /// The trace at `idx` in the xref data and its ops
+    pub fn trace(&self, idx: usize) -> (&'x SymbolTrace, &[TraceOp]) {
+        (&self.xref_data.traces[idx], &self.ops[idx])
+    }
+}
+
+#[derive(Serialize, Debug)]
+pub struct OutputSymbol<'a> {
+    pub symbol: &'a str,
+    pub offset: u64,
+    /// How likely `offset` is to be right, from 0 to 1
+    pub confidence: f32,
+    pub validation: Validation,
+    /// The number of traces that found this symbol, all of which agree
+    pub traces: usize,
+}
+
+/// A symbol whose traces found different addresses
+#[derive(Serialize, Debug)]
+pub struct Conflict<'a> {
+    pub symbol: &'a str,
+    pub candidates: Vec<Candidate<'a>>,
+}
+
+impl<'a> fmt::Display for Conflict<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "traces for symbol '{}' disagree:", self.symbol)?;
+        for candidate in &self.candidates {
+            write!(
+                f,
+                " '{}' from '{}' found {:#x};",
+                candidate.trace, candidate.start, candidate.offset
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// The address one of the traces of a [`Conflict`] found
+#[derive(Serialize, Debug)]
+pub struct Candidate<'a> {
+    pub start: &'a str,
+    pub trace: &'a str,
+    pub offset: u64,
+}
+
+/// A trace that could not be followed to the end
+#[derive(Serialize, Debug)]
+pub struct TraceFailure<'a> {
+    pub symbol: &'a str,
+    pub start: &'a str,
+    /// The index of the trace op being followed when the trace failed, or
+    /// `None` if it failed before reaching the first op
+    pub op_index: Option<usize>,
+    /// The last address the walk reached
+    pub addr: Option<u64>,
+    pub kind: FailureKind,
+    pub message: String,
+}
+
+impl<'a> fmt::Display for TraceFailure<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "failed to trace symbol '{}' starting at '{}'",
+            self.symbol, self.start
+        )?;
+        if let Some(op_index) = self.op_index {
+            write!(f, " (op {}", op_index)?;
+            if let Some(addr) = self.addr {
+                write!(f, " at {:#x}", addr)?;
+            }
+            write!(f, ")")?;
+        }
+        write!(f, ": {}", self.message)
+    }
+}
+
+#[derive(Serialize, Debug, Default)]
+pub struct Output<'a> {
+    pub symbols: Vec<OutputSymbol<'a>>,
+    /// Every trace that failed, including alternatives for symbols that were
+    /// found by another trace
+    pub failures: Vec<TraceFailure<'a>>,
+    pub conflicts: Vec<Conflict<'a>>,
+    /// Methods requested by trace starts that are not in the metadata
+    pub unresolved_roots: Vec<UnresolvedRoot>,
+    /// Methods requested by trace starts that have no compiled code
+    pub null_methods: Vec<NullMethod>,
+    /// Why the methods requested by trace starts could not be looked up at
+    /// all, in which case each of their traces fails with the same reason
+    pub roots_unavailable: Option<String>,
+}
+
+/// Builder for [`XRefApply`], taking the contents of the input files, either
+/// memory-mapped with [`InputFile`] or already in memory.
+#[derive(Default)]
+pub struct XRefApplyBuilder<'data> {
+    shared_object: Option<&'data [u8]>,
+    metadata: Option<&'data [u8]>,
+    null_method_fallback: NullMethodFallback,
+}
+
+impl<'data> XRefApplyBuilder<'data> {
+    /// The contents of the application's il2cpp shared object file (libil2cpp.so)
+    pub fn shared_object(mut self, data: &'data [u8]) -> Self {
+        self.shared_object = Some(data);
+        self
+    }
+
+    /// The contents of the application's il2cpp metadata file (global-metadata.dat)
+    pub fn metadata(mut self, data: &'data [u8]) -> Self {
+        self.metadata = Some(data);
+        self
+    }
+
+    /// Where traces from il2cpp methods without compiled code start instead.
+    /// Defaults to [`NullMethodFallback::None`], failing those traces.
+    pub fn null_method_fallback(mut self, fallback: NullMethodFallback) -> Self {
+        self.null_method_fallback = fallback;
+        self
+    }
+
+    pub fn build(self) -> Result<XRefApply<'data>> {
+        let elf_data = self.shared_object.context("no shared object provided")?;
+        let metadata_data = self.metadata.context("no metadata provided")?;
+
+        let metadata = il2cpp_metadata_raw::deserialize(metadata_data)?;
+        let (shared_object, registrations) = match FileKind::parse(elf_data)? {
+            FileKind::Elf64 => {
+                let elf = Elf::parse(elf_data)?;
+                // il2cpp_binary only finds the registrations of AArch64 code
+                if elf.architecture() == Architecture::Aarch64 {
+                    let (code_registration, metadata_registration) =
+                        il2cpp_binary::registrations(&elf, &metadata)?;
+                    let shared_object = SharedObject::new(&elf, Some(&code_registration))?;
+                    (
+                        shared_object,
+                        Some((code_registration, metadata_registration)),
+                    )
+                } else {
+                    (SharedObject::new(&elf, None)?, None)
+                }
+            }
+            FileKind::Elf32 => {
+                // il2cpp_binary::registrations only takes 64-bit ELF files and
+                // finds the registrations by decoding AArch64 code, so 32-bit
+                // shared objects are traced without them
+                let elf = ElfFile32::<Endianness>::parse(elf_data)?;
+                (SharedObject::new(&elf, None)?, None)
+            }
+            kind => bail!("the shared object is not an ELF file, found {:?}", kind),
+        };
+
+        Ok(XRefApply {
+            metadata,
+            registrations,
+            shared_object,
+            null_method_fallback: self.null_method_fallback,
+        })
+    }
+}
+
+/// A parsed il2cpp application that xref traces can be applied to.
+pub struct XRefApply<'data> {
+    metadata: Metadata<'data>,
+    /// The il2cpp registrations, which can only be found in AArch64 shared
+    /// objects
+    registrations: Option<(CodeRegistration<'data>, MetadataRegistration)>,
+    shared_object: SharedObject<'data>,
+    null_method_fallback: NullMethodFallback,
+}
+
+impl<'data> XRefApply<'data> {
+    pub fn builder() -> XRefApplyBuilder<'data> {
+        XRefApplyBuilder::default()
+    }
+
+    /// Creates a tracer with the roots required by the traces in `xref_data`.
+    pub fn tracer<'a>(&'a self, xref_data: &'a XRefData) -> Result<XRefTracer<'a>> {
+        let roots = match &self.registrations {
+            Some((code_registration, metadata_registration)) => find_roots(
+                &self.metadata,
+                metadata_registration,
+                code_registration,
+                self.null_method_fallback,
+                xref_data,
+            )?,
+            None => Roots::unavailable(
+                xref_data,
+                &format!(
+                    "il2cpp methods can only be found in AArch64 shared objects, not {}",
+                    self.shared_object.arch.name()
+                ),
+            ),
+        };
+        Ok(XRefTracer::new(&self.shared_object, roots))
+    }
+
+    pub fn trace<'x>(&self, xref_data: &'x XRefData) -> Result<Output<'x>> {
+        self.tracer(xref_data)?.trace_all(xref_data)
+    }
+}
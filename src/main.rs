@@ -1,59 +1,106 @@
-use bad64::{Imm, Instruction, Op, Operand};
-use clap::Parser;
-use color_eyre::eyre::{bail, eyre, ContextCompat, Result};
-use il2cpp_binary::{CodeRegistration, Elf};
-use il2cpp_metadata_raw::Metadata;
-use object::{Object, ObjectSymbol};
-use serde::{Deserialize, Serialize};
-use std::collections::{HashMap, HashSet};
+use clap::{Args, Parser, Subcommand};
+use color_eyre::eyre::Result;
 use std::fs;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use xref_apply::batch::{Manifest, Summary, SUMMARY_NAME};
+use xref_apply::{InputFile, NullMethodFallback, Output, ParsedOps, XRefApply, XRefData};
 
 #[derive(Parser, Debug)]
-#[clap(author, version, about, long_about = None)]
-struct Args {
+#[clap(
+    author,
+    version,
+    about,
+    long_about = None,
+    args_conflicts_with_subcommands = true,
+    subcommand_negates_reqs = true
+)]
+struct Cli {
+    #[clap(subcommand)]
+    command: Option<Command>,
     /// The path to the application's il2cpp shared object file (libil2cpp.so)
-    shared_object: PathBuf,
+    #[clap(required = true)]
+    shared_object: Option<PathBuf>,
     /// The path to the application's il2cpp metadata file (global-metadata.dat)
-    metadata: PathBuf,
+    #[clap(required = true)]
+    metadata: Option<PathBuf>,
     /// The path to the xref data created by xref_gen
-    xref_data: PathBuf,
+    #[clap(required = true)]
+    xref_data: Option<PathBuf>,
+    #[clap(flatten)]
+    options: Options,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Apply the same xref data to every build listed in a manifest, writing
+    /// an output for each build and a summary of which symbols were found in
+    /// which build
+    Batch {
+        /// The path to the manifest, a JSON file listing the name, shared
+        /// object and metadata of each build. Relative paths are relative to
+        /// the manifest.
+        manifest: PathBuf,
+        /// The path to the xref data created by xref_gen
+        xref_data: PathBuf,
+        #[clap(flatten)]
+        options: Options,
+    },
+}
+
+#[derive(Args, Debug)]
+struct Options {
     /// The output directory to place script and script data into
     #[clap(short, long, default_value = "./data")]
     output_dir: PathBuf,
+    /// Where traces from il2cpp methods without compiled code start instead
+    /// (none, invoker or unresolved-virtual-call)
+    #[clap(long, default_value = "none")]
+    null_method_fallback: NullMethodFallback,
+    /// The number of threads to trace with, one per core by default
+    #[clap(short, long)]
+    jobs: Option<usize>,
 }
 
 fn main() -> Result<()> {
     color_eyre::install()?;
-    let args = Args::parse();
-
-    let elf_data = fs::read(&args.shared_object)?;
-    let elf = Elf::parse(&elf_data)?;
-
-    let metadata_data = fs::read(&args.metadata)?;
-    let metadata = il2cpp_metadata_raw::deserialize(&metadata_data)?;
-    let (cr, _) = il2cpp_binary::registrations(&elf, &metadata)?;
-
-    let xref_data = serde_json::from_str(&fs::read_to_string(&args.xref_data)?)?;
-    let roots = find_roots(&metadata, &cr, &xref_data)?;
-
-    let mut symbols = HashMap::new();
-    for symbol in elf.dynamic_symbols() {
-        if symbol.is_definition() {
-            symbols.insert(symbol.name()?, symbol.address());
-        }
+    let cli = Cli::parse();
+
+    match cli.command {
+        Some(Command::Batch {
+            manifest,
+            xref_data,
+            options,
+        }) => batch(&manifest, &xref_data, &options),
+        // Without a subcommand, clap requires all of the paths
+        None => single(
+            &cli.shared_object.unwrap(),
+            &cli.metadata.unwrap(),
+            &cli.xref_data.unwrap(),
+            &cli.options,
+        ),
     }
+}
 
-    let tracer = XRefTracer {
-        elf: &elf,
-        roots,
-        symbols,
-    };
+fn single(
+    shared_object: &Path,
+    metadata: &Path,
+    xref_data: &Path,
+    options: &Options,
+) -> Result<()> {
+    init_threads(options)?;
+    let xref_data = read_xref_data(xref_data)?;
+    let ops = xref_data.parse_ops()?;
     println!("tracing all symbols.");
-    let output = tracer.trace_all(&xref_data)?;
+    let output = apply(shared_object, metadata, &ops, options)?;
+    for failure in &output.failures {
+        eprintln!("{}", failure);
+    }
+    for conflict in &output.conflicts {
+        eprintln!("{}", conflict);
+    }
 
     fs::write(
-        args.output_dir.join("xref_apply.json"),
+        options.output_dir.join("xref_apply.json"),
         serde_json::to_string(&output)?,
     )?;
     println!("trace complete.");
@@ -62,239 +109,86 @@ fn main() -> Result<()> {
     Ok(())
 }
 
-fn offset_len(offset: u32, len: u32) -> std::ops::Range<usize> {
-    if (offset as i32) < 0 {
-        return 0..0;
-    }
-    offset as usize..offset as usize + len as usize
-}
-
-fn find_roots<'md>(
-    metadata: &'md Metadata,
-    code_registration: &CodeRegistration,
-    xref_data: &XRefData,
-) -> Result<Roots<'md>> {
-    let mut required_roots = HashSet::new();
-    for trace in &xref_data.traces {
-        if trace.start.starts_with("il2cpp:") || trace.start.starts_with("invoker:") {
-            let parts: Vec<&str> = trace.start.split(':').collect();
-            let namespace = parts[1];
-            let class = parts[2];
-            let method_name = parts[3];
-            required_roots.insert((namespace, class, method_name));
-        }
-    }
-
-    let mut roots = HashMap::new();
-    for image in &metadata.images {
-        let image_name = metadata.get_str(image.name_index)?;
-        let type_defs_range = offset_len(image.type_start, image.type_count);
-        for type_def in &metadata.type_definitions[type_defs_range] {
-            let method_range = offset_len(type_def.method_start, type_def.method_count as u32);
-            let namespace = metadata.get_str(type_def.namespace_index)?;
-            let class = metadata.get_str(type_def.name_index)?;
-            for method in &metadata.methods[method_range] {
-                let method_name = metadata.get_str(method.name_index)?;
-                if required_roots
-                    .take(&(namespace, class, method_name))
-                    .is_some()
-                {
-                    let root = Root::get(method.token, image_name, code_registration)?;
-                    roots.insert((namespace, class, method_name), root);
-                }
+fn batch(manifest_path: &Path, xref_data: &Path, options: &Options) -> Result<()> {
+    init_threads(options)?;
+    let manifest: Manifest = serde_json::from_str(&fs::read_to_string(manifest_path)?)?;
+    manifest.validate()?;
+    let manifest_dir = manifest_path.parent().unwrap_or_else(|| Path::new(""));
+    let xref_data = read_xref_data(xref_data)?;
+    // Syntax errors stop the whole batch instead of failing every build
+    let ops = xref_data.parse_ops()?;
+
+    let mut summary = Summary::new(&xref_data);
+    for build in &manifest.builds {
+        println!("tracing build '{}'.", build.name);
+        let output = apply(
+            &manifest_dir.join(&build.shared_object),
+            &manifest_dir.join(&build.metadata),
+            &ops,
+            options,
+        );
+        match &output {
+            Ok(output) => {
+                println!(
+                    "found {} of {} symbols in build '{}'.",
+                    output.symbols.len(),
+                    summary.symbols.len(),
+                    build.name
+                );
+                fs::write(
+                    options.output_dir.join(format!("{}.json", build.name)),
+                    serde_json::to_string(output)?,
+                )?;
             }
+            Err(err) => eprintln!("could not trace build '{}': {:#}", build.name, err),
         }
+        summary.add(&build.name, &output);
     }
 
-    Ok(roots)
-}
-
-type Roots<'a> = HashMap<(&'a str, &'a str, &'a str), Root>;
-
-#[derive(Debug)]
-struct Root {
-    method_addr: u64,
-    invoker_addr: Option<u64>,
-}
-
-impl Root {
-    fn get(token: u32, image_name: &str, code_registration: &CodeRegistration) -> Result<Self> {
-        let rid = 0x00FFFFFF & token;
-        let module = code_registration
-            .code_gen_modules
-            .iter()
-            .find(|module| module.name == image_name)
-            .context("could not find module for xref trace")?;
-
-        let method_addr = module.method_pointers[rid as usize - 1];
-        let invoker_idx = module.invoker_indices[rid as usize - 1];
-        let invoker_addr = if invoker_idx == u32::MAX {
-            None
-        } else {
-            Some(code_registration.invoker_pointers[invoker_idx as usize])
-        };
-
-        Ok(Self {
-            method_addr,
-            invoker_addr,
-        })
-    }
-}
-
-struct XRefTracer<'a> {
-    elf: &'a Elf<'a>,
-    roots: Roots<'a>,
-    symbols: HashMap<&'a str, u64>,
+    fs::write(
+        options.output_dir.join(format!("{}.json", SUMMARY_NAME)),
+        serde_json::to_string(&summary)?,
+    )?;
+    println!("batch complete.");
+    Ok(())
 }
 
-impl<'a> XRefTracer<'a> {
-    fn trace_all(&self, xref_data: &'a XRefData) -> Result<Output<'a>> {
-        let mut symbols = Vec::new();
-        for trace in &xref_data.traces {
-            match self.trace_single(trace) {
-                Ok(symbol) => symbols.push(symbol),
-                Err(err) => eprintln!(
-                    "{:?}",
-                    err.wrap_err(format!(
-                        "failed to trace symbol '{}' starting at '{}'",
-                        trace.symbol, trace.start
-                    ))
-                ),
-            };
-        }
-        Ok(Output { symbols })
+/// Parses the inputs of one build and traces every symbol in `xref_data`,
+/// reporting the roots that could not be used before tracing.
+fn apply<'x>(
+    shared_object: &Path,
+    metadata: &Path,
+    ops: &ParsedOps<'x>,
+    options: &Options,
+) -> Result<Output<'x>> {
+    let elf_data = InputFile::open(shared_object)?;
+    let metadata_data = InputFile::open(metadata)?;
+    let xref_apply = XRefApply::builder()
+        .shared_object(&elf_data)
+        .metadata(&metadata_data)
+        .null_method_fallback(options.null_method_fallback)
+        .build()?;
+
+    let tracer = xref_apply.tracer(ops.xref_data())?;
+    if let Some(reason) = tracer.roots_unavailable() {
+        eprintln!("il2cpp starts cannot be traced: {}", reason);
     }
-
-    fn trace_single(&self, trace: &'a SymbolTrace) -> Result<OutputSymbol<'a>> {
-        let start: u64 = if trace.start.starts_with("il2cpp:") {
-            let parts: Vec<&str> = trace.start.split(':').collect();
-            let root = &self.roots[&(parts[1], parts[2], parts[3])];
-            root.method_addr
-        } else if trace.start.starts_with("invoker:") {
-            let parts: Vec<&str> = trace.start.split(':').collect();
-            let root = &self.roots[&(parts[1], parts[2], parts[3])];
-            root.invoker_addr
-                .context("root does not have invoker pointer")?
-        } else {
-            self.symbols[trace.start.as_str()]
-        };
-
-        let nums = trace
-            .trace
-            .split(|c| ('A'..='Z').contains(&c))
-            .filter(|s| !s.is_empty())
-            .map(|s| s.parse::<usize>());
-        let ops = trace.trace.chars().filter(|&c| char::is_alphabetic(c));
-
-        let mut addr = start;
-        for (op, num) in ops.zip(nums) {
-            let num = num?;
-            let mut count = 0;
-            loop {
-                let ins = self.load_ins(addr)?;
-                match ins.op() {
-                    Op::BL if op == 'L' => {
-                        if count == num {
-                            let to = match ins.operands()[0] {
-                                Operand::Label(Imm::Unsigned(to)) => to,
-                                _ => bail!("bl had wrong operand"),
-                            };
-                            addr = to as _;
-                            break;
-                        }
-                        count += 1;
-                    }
-                    Op::B if op == 'B' => {
-                        if count == num {
-                            let to = match ins.operands()[0] {
-                                Operand::Label(Imm::Unsigned(to)) => to,
-                                _ => bail!("b had wrong operand"),
-                            };
-                            addr = to as _;
-                            break;
-                        }
-                        count += 1;
-                    }
-                    Op::ADRP if op == 'P' => {
-                        if count == num {
-                            let (base, reg) = match ins.operands() {
-                                [Operand::Reg { reg, .. }, Operand::Label(Imm::Unsigned(imm))] => {
-                                    (*imm, *reg)
-                                }
-                                _ => bail!("adrp had wrong operands"),
-                            };
-                            loop {
-                                addr += 4;
-                                let ins = self.load_ins(addr)?;
-                                match (ins.op(), ins.operands()) {
-                                    (
-                                        Op::LDR,
-                                        [Operand::Reg { .. }, Operand::MemOffset {
-                                            reg: a,
-                                            offset: Imm::Signed(imm),
-                                            ..
-                                        }],
-                                    ) if reg == *a => {
-                                        addr = ((base as i64) + imm) as _;
-                                        break;
-                                    }
-                                    (
-                                        Op::ADD,
-                                        [Operand::Reg { .. }, Operand::Reg { reg: a, .. }, Operand::Imm64 {
-                                            imm: Imm::Unsigned(imm),
-                                            ..
-                                        }],
-                                    ) if reg == *a => {
-                                        addr = (base + imm) as _;
-                                        break;
-                                    }
-                                    _ => {}
-                                }
-                            }
-                            break;
-                        }
-                        count += 1;
-                    }
-                    _ => {}
-                }
-                addr += 4;
-            }
-        }
-
-        Ok(OutputSymbol {
-            offset: addr,
-            symbol: &trace.symbol,
-        })
+    for root in tracer.unresolved_roots() {
+        eprintln!("{}", root);
     }
-
-    fn load_ins(&self, addr: u64) -> Result<Instruction> {
-        let addr = addr as usize;
-        let data = &self.elf.data()[addr..addr + 4];
-        let data = u32::from_le_bytes([data[0], data[1], data[2], data[3]]);
-        bad64::decode(data, addr as u64)
-            .map_err(|err| eyre!("decode error during xref walk: {}", err))
+    for method in tracer.null_methods() {
+        eprintln!("{}", method);
     }
+    Ok(tracer.trace_parsed(ops))
 }
 
-#[derive(Deserialize, Debug)]
-struct SymbolTrace {
-    symbol: String,
-    start: String,
-    trace: String,
+fn read_xref_data(path: &Path) -> Result<XRefData> {
+    Ok(serde_json::from_str(&fs::read_to_string(path)?)?)
 }
 
-#[derive(Deserialize)]
-pub struct XRefData {
-    traces: Vec<SymbolTrace>,
-}
-
-#[derive(Serialize, Debug)]
-struct OutputSymbol<'a> {
-    symbol: &'a str,
-    offset: u64,
-}
-
-#[derive(Serialize, Debug)]
-struct Output<'a> {
-    symbols: Vec<OutputSymbol<'a>>,
+fn init_threads(options: &Options) -> Result<()> {
+    rayon::ThreadPoolBuilder::new()
+        .num_threads(options.jobs.unwrap_or(0))
+        .build_global()?;
+    Ok(())
 }
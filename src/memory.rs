@@ -0,0 +1,225 @@
+use color_eyre::eyre::Result;
+use object::elf::PF_X;
+use object::{Object, ObjectSegment, SegmentFlags};
+use std::fmt;
+
+/// A `PT_LOAD` segment of the shared object as it would be mapped into memory.
+#[derive(Debug)]
+struct Segment<'data> {
+    address: u64,
+    /// The size of the segment in memory (`p_memsz`)
+    size: u64,
+    /// The file-backed part of the segment (`p_filesz` bytes). Anything past
+    /// this up to `size` is zero-initialized at load time (BSS).
+    data: &'data [u8],
+    executable: bool,
+}
+
+impl<'data> Segment<'data> {
+    fn contains(&self, addr: u64) -> bool {
+        addr >= self.address && addr - self.address < self.size
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemoryError {
+    /// The address is not inside any loadable segment
+    Unmapped { addr: u64 },
+    /// The address is inside a segment but has no file contents (e.g. BSS)
+    NotFileBacked { addr: u64 },
+    /// The read starts inside a segment but runs past its end
+    CrossesSegment { addr: u64, len: usize },
+}
+
+impl fmt::Display for MemoryError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            MemoryError::Unmapped { addr } => {
+                write!(f, "address {:#x} is not mapped by any segment", addr)
+            }
+            MemoryError::NotFileBacked { addr } => {
+                write!(f, "address {:#x} has no file contents (bss)", addr)
+            }
+            MemoryError::CrossesSegment { addr, len } => write!(
+                f,
+                "read of {} bytes at {:#x} crosses a segment boundary",
+                len, addr
+            ),
+        }
+    }
+}
+
+impl std::error::Error for MemoryError {}
+
+/// The virtual address space of the shared object, built from its program
+/// headers. Translates virtual addresses to the file contents backing them.
+#[derive(Debug)]
+pub struct AddressSpace<'data> {
+    segments: Vec<Segment<'data>>,
+}
+
+impl<'data> AddressSpace<'data> {
+    pub fn new<'file, O>(elf: &'file O) -> Result<Self>
+    where
+        'data: 'file,
+        O: Object<'data, 'file>,
+    {
+        let mut segments = Vec::new();
+        for segment in elf.segments() {
+            let executable = match segment.flags() {
+                SegmentFlags::Elf { p_flags } => p_flags & PF_X != 0,
+                _ => false,
+            };
+            segments.push(Segment {
+                address: segment.address(),
+                size: segment.size(),
+                data: segment.data()?,
+                executable,
+            });
+        }
+        Ok(Self { segments })
+    }
+
+    fn segment(&self, addr: u64) -> Option<&Segment<'data>> {
+        self.segments.iter().find(|segment| segment.contains(addr))
+    }
+
+    /// Reads `len` bytes starting at the virtual address `addr`.
+    pub fn read(&self, addr: u64, len: usize) -> Result<&'data [u8], MemoryError> {
+        let segment = self.segment(addr).ok_or(MemoryError::Unmapped { addr })?;
+        let start = addr - segment.address;
+        let end = start + len as u64;
+        if end > segment.size {
+            return Err(MemoryError::CrossesSegment { addr, len });
+        }
+        if end > segment.data.len() as u64 {
+            return Err(MemoryError::NotFileBacked { addr });
+        }
+        Ok(&segment.data[start as usize..end as usize])
+    }
+
+    pub fn read_u32(&self, addr: u64) -> Result<u32, MemoryError> {
+        let data = self.read(addr, 4)?;
+        Ok(u32::from_le_bytes([data[0], data[1], data[2], data[3]]))
+    }
+
+    pub fn read_u64(&self, addr: u64) -> Result<u64, MemoryError> {
+        let data = self.read(addr, 8)?;
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(data);
+        Ok(u64::from_le_bytes(bytes))
+    }
+
+    /// Whether `addr` is in a segment that is mapped as executable
+    pub fn is_executable(&self, addr: u64) -> bool {
+        self.segment(addr).is_some_and(|segment| segment.executable)
+    }
+}
+
+#[cfg(test)]
+impl<'data> AddressSpace<'data> {
+    /// An address space of executable segments holding each `(address,
+    /// data)`, without any BSS
+    pub(crate) fn from_raw(segments: &[(u64, &'data [u8])]) -> Self {
+        Self {
+            segments: segments
+                .iter()
+                .map(|&(address, data)| Segment {
+                    address,
+                    size: data.len() as u64,
+                    data,
+                    executable: true,
+                })
+                .collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A file whose bytes are their own offsets, with a code segment at file
+    /// offset 0x100 loaded at 0x1100 and a data segment at file offset 0x200
+    /// loaded at 0x3200 with 0x100 bytes of BSS after it
+    fn address_space(file: &[u8]) -> AddressSpace<'_> {
+        AddressSpace {
+            segments: vec![
+                Segment {
+                    address: 0x1100,
+                    size: 0x100,
+                    data: &file[0x100..0x200],
+                    executable: true,
+                },
+                Segment {
+                    address: 0x3200,
+                    size: 0x200,
+                    data: &file[0x200..0x300],
+                    executable: false,
+                },
+            ],
+        }
+    }
+
+    fn file() -> Vec<u8> {
+        (0..0x300).map(|offset| offset as u8).collect()
+    }
+
+    #[test]
+    fn translates_addresses_through_segments() {
+        let file = file();
+        let memory = address_space(&file);
+        assert_eq!(memory.read(0x1100, 2).unwrap(), [0x00, 0x01]);
+        assert_eq!(memory.read(0x1110, 1).unwrap(), [0x10]);
+        assert_eq!(memory.read_u32(0x3204).unwrap(), 0x0706_0504);
+        assert_eq!(memory.read_u64(0x32f8).unwrap(), 0xfffe_fdfc_fbfa_f9f8);
+        assert!(memory.is_executable(0x11ff));
+        assert!(!memory.is_executable(0x3200));
+        assert!(!memory.is_executable(0x100));
+    }
+
+    #[test]
+    fn reports_unmapped_addresses() {
+        let file = file();
+        let memory = address_space(&file);
+        // The file offsets of the segments are not their addresses
+        for addr in [0x0, 0x100, 0x200, 0x1200, 0x3400] {
+            assert_eq!(memory.read(addr, 1), Err(MemoryError::Unmapped { addr }));
+        }
+    }
+
+    #[test]
+    fn reports_reads_into_bss() {
+        let file = file();
+        let memory = address_space(&file);
+        assert_eq!(
+            memory.read(0x3300, 4),
+            Err(MemoryError::NotFileBacked { addr: 0x3300 })
+        );
+        // Starting in the file contents and running into BSS
+        assert_eq!(
+            memory.read_u64(0x32fc),
+            Err(MemoryError::NotFileBacked { addr: 0x32fc })
+        );
+    }
+
+    #[test]
+    fn reports_reads_past_a_segment() {
+        let file = file();
+        let memory = address_space(&file);
+        assert_eq!(
+            memory.read_u32(0x11fe),
+            Err(MemoryError::CrossesSegment {
+                addr: 0x11fe,
+                len: 4
+            })
+        );
+        assert_eq!(
+            memory.read(0x33ff, 2),
+            Err(MemoryError::CrossesSegment {
+                addr: 0x33ff,
+                len: 2
+            })
+        );
+    }
+}
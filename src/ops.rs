@@ -0,0 +1,433 @@
+//! The trace string language.
+//!
+//! A trace is a sequence of ops, each of which finds the nth instruction of
+//! some kind starting at the current address and moves to wherever it leads:
+//!
+//! ```text
+//! trace = { op } ;
+//! op    = "L" count                 (* follow the target of a BL *)
+//!       | "B" count                 (* follow the target of an unconditional B *)
+//!       | "P" count [ "." count ]   (* the address formed by an ADRP and its user *)
+//!       | "C" [ cond ] count        (* follow the target of a B.cond *)
+//!       | "Z" [ zero ] count        (* follow the target of a CBZ/CBNZ *)
+//!       | "T" [ zero ] count        (* follow the target of a TBZ/TBNZ *)
+//!       | "R" count                 (* follow the target of a BLR/BR *)
+//!       | "D" ;                     (* the pointer stored at the current address *)
+//! cond  = "eq" | "ne" | "cs" | "hs" | "cc" | "lo" | "mi" | "pl"
+//!       | "vs" | "vc" | "hi" | "ls" | "ge" | "lt" | "gt" | "le" ;
+//! zero  = "z" | "nz" ;
+//! count = digit { digit } ;
+//! ```
+//!
+//! Without a `cond` or `zero` filter, conditional branch ops count every
+//! branch of their kind. With one, only the branches taken on that condition
+//! are counted, e.g. `Cne0` follows the first `B.NE` and `Znz1` follows the
+//! second `CBNZ`.
+//!
+//! The target of an indirect branch is found by tracking its register back to
+//! the `ADRP`, `ADD`, `LDR` and `MOV` instructions that produced its value.
+//!
+//! `P` follows the register an `ADRP` writes to the instructions that use it
+//! as a base, such as `ADD`, `LDR`, `LDRB`, `LDRSW` or `STR`. The optional
+//! second count picks which of them to use when there are several, so `P0.1`
+//! is the address used by the second user of the first `ADRP`.
+//!
+//! `D` reads the pointer stored at the address reached so far, such as a GOT
+//! slot found with `P`, applying its dynamic relocation if it has one.
+//!
+//! Counts are zero-based, so `L2B0` follows the third `BL` and then the first
+//! `B` after its target.
+//!
+//! Instructions are named as on AArch64. Other architectures count their
+//! equivalents, e.g. `BLX` for `BL` and `MOVW` for `ADRP` on ARMv7, or `CALL`
+//! for `BL` and a RIP-relative `LEA` for `ADRP` on x86_64.
+
+use std::fmt;
+use std::iter::Peekable;
+use std::str::CharIndices;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum TraceOp {
+    /// `L<n>`: follow the nth `BL`
+    Call(usize),
+    /// `B<n>`: follow the nth unconditional `B`
+    Branch(usize),
+    /// `P<n>[.<m>]`: the address formed by the nth `ADRP` and the mth
+    /// instruction that uses its register as a base
+    Adrp(usize, usize),
+    /// `C[cond]<n>`: follow the nth `B.cond`, optionally only counting those
+    /// with the given condition
+    CondBranch(Option<Cond>, usize),
+    /// `Z[zero]<n>`: follow the nth `CBZ`/`CBNZ`
+    CompareBranch(Option<ZeroCond>, usize),
+    /// `T[zero]<n>`: follow the nth `TBZ`/`TBNZ`
+    TestBranch(Option<ZeroCond>, usize),
+    /// `R<n>`: follow the nth `BLR`/`BR`
+    Indirect(usize),
+    /// `D`: read the pointer at the current address
+    Deref,
+}
+
+impl TraceOp {
+    /// The zero-based number of matching instructions to skip, or `None` for
+    /// ops that do not look for an instruction
+    pub fn count(self) -> Option<usize> {
+        match self {
+            TraceOp::Call(n)
+            | TraceOp::Branch(n)
+            | TraceOp::Adrp(n, _)
+            | TraceOp::CondBranch(_, n)
+            | TraceOp::CompareBranch(_, n)
+            | TraceOp::TestBranch(_, n)
+            | TraceOp::Indirect(n) => Some(n),
+            TraceOp::Deref => None,
+        }
+    }
+}
+
+impl fmt::Display for TraceOp {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            TraceOp::Call(n) => write!(f, "L{}", n),
+            TraceOp::Branch(n) => write!(f, "B{}", n),
+            TraceOp::Adrp(n, 0) => write!(f, "P{}", n),
+            TraceOp::Adrp(n, user) => write!(f, "P{}.{}", n, user),
+            TraceOp::CondBranch(cond, n) => write!(f, "C{}{}", OptDisplay(cond), n),
+            TraceOp::CompareBranch(zero, n) => write!(f, "Z{}{}", OptDisplay(zero), n),
+            TraceOp::TestBranch(zero, n) => write!(f, "T{}{}", OptDisplay(zero), n),
+            TraceOp::Indirect(n) => write!(f, "R{}", n),
+            TraceOp::Deref => write!(f, "D"),
+        }
+    }
+}
+
+struct OptDisplay<T>(Option<T>);
+
+impl<T: fmt::Display> fmt::Display for OptDisplay<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.0 {
+            Some(value) => value.fmt(f),
+            None => Ok(()),
+        }
+    }
+}
+
+/// The condition code of a `B.cond`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Cond {
+    Eq,
+    Ne,
+    Cs,
+    Cc,
+    Mi,
+    Pl,
+    Vs,
+    Vc,
+    Hi,
+    Ls,
+    Ge,
+    Lt,
+    Gt,
+    Le,
+}
+
+impl Cond {
+    fn from_name(name: &str) -> Option<Self> {
+        Some(match name {
+            "eq" => Cond::Eq,
+            "ne" => Cond::Ne,
+            "cs" | "hs" => Cond::Cs,
+            "cc" | "lo" => Cond::Cc,
+            "mi" => Cond::Mi,
+            "pl" => Cond::Pl,
+            "vs" => Cond::Vs,
+            "vc" => Cond::Vc,
+            "hi" => Cond::Hi,
+            "ls" => Cond::Ls,
+            "ge" => Cond::Ge,
+            "lt" => Cond::Lt,
+            "gt" => Cond::Gt,
+            "le" => Cond::Le,
+            _ => return None,
+        })
+    }
+}
+
+impl fmt::Display for Cond {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Cond::Eq => "eq",
+            Cond::Ne => "ne",
+            Cond::Cs => "cs",
+            Cond::Cc => "cc",
+            Cond::Mi => "mi",
+            Cond::Pl => "pl",
+            Cond::Vs => "vs",
+            Cond::Vc => "vc",
+            Cond::Hi => "hi",
+            Cond::Ls => "ls",
+            Cond::Ge => "ge",
+            Cond::Lt => "lt",
+            Cond::Gt => "gt",
+            Cond::Le => "le",
+        })
+    }
+}
+
+/// Whether a `CBZ`/`TBZ` style branch is taken on zero or non-zero
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ZeroCond {
+    Zero,
+    NonZero,
+}
+
+impl ZeroCond {
+    fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "z" => Some(ZeroCond::Zero),
+            "nz" => Some(ZeroCond::NonZero),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for ZeroCond {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            ZeroCond::Zero => "z",
+            ZeroCond::NonZero => "nz",
+        })
+    }
+}
+
+/// A syntax error in a trace string
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SyntaxError {
+    /// Byte offset into the trace string
+    pub position: usize,
+    pub message: String,
+}
+
+impl SyntaxError {
+    fn new(position: usize, message: impl Into<String>) -> Self {
+        Self {
+            position,
+            message: message.into(),
+        }
+    }
+}
+
+impl fmt::Display for SyntaxError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} at position {}", self.message, self.position)
+    }
+}
+
+impl std::error::Error for SyntaxError {}
+
+/// Parses a trace string such as `L2B0P1` into its ops.
+pub fn parse_trace(trace: &str) -> Result<Vec<TraceOp>, SyntaxError> {
+    let mut parser = Parser {
+        chars: trace.char_indices().peekable(),
+        len: trace.len(),
+    };
+    let mut ops = Vec::new();
+    while parser.peek().is_some() {
+        ops.push(parser.op()?);
+    }
+    Ok(ops)
+}
+
+struct Parser<'a> {
+    chars: Peekable<CharIndices<'a>>,
+    len: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn peek(&mut self) -> Option<(usize, char)> {
+        self.chars.peek().copied()
+    }
+
+    fn pos(&mut self) -> usize {
+        self.peek().map_or(self.len, |(pos, _)| pos)
+    }
+
+    fn op(&mut self) -> Result<TraceOp, SyntaxError> {
+        let (pos, c) = match self.chars.next() {
+            Some(next) => next,
+            None => return Err(SyntaxError::new(self.len, "expected an op")),
+        };
+        match c {
+            'L' => Ok(TraceOp::Call(self.plain_count(c)?)),
+            'B' => Ok(TraceOp::Branch(self.plain_count(c)?)),
+            'P' => {
+                let n = self.plain_count(c)?;
+                let user = match self.peek() {
+                    Some((_, '.')) => {
+                        self.chars.next();
+                        self.count()?
+                    }
+                    _ => 0,
+                };
+                Ok(TraceOp::Adrp(n, user))
+            }
+            'R' => Ok(TraceOp::Indirect(self.plain_count(c)?)),
+            'D' => match self.peek() {
+                Some((pos, c)) if c.is_ascii_alphanumeric() && !c.is_ascii_uppercase() => Err(
+                    SyntaxError::new(pos, "op 'D' does not take a count or condition"),
+                ),
+                _ => Ok(TraceOp::Deref),
+            },
+            'C' => {
+                let cond = self.filter("condition", Cond::from_name)?;
+                Ok(TraceOp::CondBranch(cond, self.count()?))
+            }
+            'Z' => {
+                let zero = self.filter("zero condition", ZeroCond::from_name)?;
+                Ok(TraceOp::CompareBranch(zero, self.count()?))
+            }
+            'T' => {
+                let zero = self.filter("zero condition", ZeroCond::from_name)?;
+                Ok(TraceOp::TestBranch(zero, self.count()?))
+            }
+            c if c.is_ascii_uppercase() => {
+                Err(SyntaxError::new(pos, format!("unknown op '{}'", c)))
+            }
+            c => Err(SyntaxError::new(
+                pos,
+                format!("expected an op, found '{}'", c),
+            )),
+        }
+    }
+
+    /// Parses the lowercase filter following an op letter, if there is one.
+    fn filter<T>(
+        &mut self,
+        what: &str,
+        from_name: fn(&str) -> Option<T>,
+    ) -> Result<Option<T>, SyntaxError> {
+        let start = self.pos();
+        let mut name = String::new();
+        while let Some((_, c)) = self.peek() {
+            if !c.is_ascii_lowercase() {
+                break;
+            }
+            self.chars.next();
+            name.push(c);
+        }
+        if name.is_empty() {
+            return Ok(None);
+        }
+        match from_name(&name) {
+            Some(filter) => Ok(Some(filter)),
+            None => Err(SyntaxError::new(
+                start,
+                format!("unknown {} '{}'", what, name),
+            )),
+        }
+    }
+
+    /// Parses the count of an op that does not take a filter.
+    fn plain_count(&mut self, op: char) -> Result<usize, SyntaxError> {
+        if let Some((pos, c)) = self.peek() {
+            if c.is_ascii_lowercase() {
+                return Err(SyntaxError::new(
+                    pos,
+                    format!("op '{}' does not take a condition", op),
+                ));
+            }
+        }
+        self.count()
+    }
+
+    fn count(&mut self) -> Result<usize, SyntaxError> {
+        let start = self.pos();
+        let mut count: usize = 0;
+        let mut digits = 0;
+        while let Some((_, c)) = self.peek() {
+            let digit = match c.to_digit(10) {
+                Some(digit) => digit,
+                None => break,
+            };
+            self.chars.next();
+            digits += 1;
+            count = count
+                .checked_mul(10)
+                .and_then(|count| count.checked_add(digit as usize))
+                .ok_or_else(|| SyntaxError::new(start, "count is too large"))?;
+        }
+        if digits == 0 {
+            return Err(SyntaxError::new(start, "expected a count"));
+        }
+        Ok(count)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn error(trace: &str) -> (usize, String) {
+        let err = parse_trace(trace).unwrap_err();
+        (err.position, err.message)
+    }
+
+    #[test]
+    fn parses_valid_traces() {
+        assert_eq!(
+            parse_trace("L2B0P1").unwrap(),
+            [TraceOp::Call(2), TraceOp::Branch(0), TraceOp::Adrp(1, 0)]
+        );
+        assert_eq!(parse_trace("P0.1").unwrap(), [TraceOp::Adrp(0, 1)]);
+        assert_eq!(
+            parse_trace("Cne0").unwrap(),
+            [TraceOp::CondBranch(Some(Cond::Ne), 0)]
+        );
+        assert_eq!(
+            parse_trace("Znz1").unwrap(),
+            [TraceOp::CompareBranch(Some(ZeroCond::NonZero), 1)]
+        );
+        assert_eq!(parse_trace("D").unwrap(), [TraceOp::Deref]);
+        assert_eq!(
+            parse_trace("C3T0R12D").unwrap(),
+            [
+                TraceOp::CondBranch(None, 3),
+                TraceOp::TestBranch(None, 0),
+                TraceOp::Indirect(12),
+                TraceOp::Deref
+            ]
+        );
+        assert_eq!(parse_trace("").unwrap(), []);
+    }
+
+    #[test]
+    fn ops_display_as_parsed() {
+        for trace in ["L2B0P1", "P0.1", "Cne0", "Znz1", "Tz3", "R0D"] {
+            let ops = parse_trace(trace).unwrap();
+            let shown: String = ops.iter().map(|op| op.to_string()).collect();
+            assert_eq!(shown, trace);
+        }
+    }
+
+    #[test]
+    fn reports_error_positions() {
+        assert_eq!(
+            error("L0Lne1"),
+            (3, "op 'L' does not take a condition".to_owned())
+        );
+        assert_eq!(error("L0B"), (3, "expected a count".to_owned()));
+        assert_eq!(error("P1."), (3, "expected a count".to_owned()));
+        assert_eq!(error("L0X1"), (2, "unknown op 'X'".to_owned()));
+        assert_eq!(error("1"), (0, "expected an op, found '1'".to_owned()));
+        assert_eq!(
+            error("D1"),
+            (1, "op 'D' does not take a count or condition".to_owned())
+        );
+        assert_eq!(
+            error("L99999999999999999999999"),
+            (1, "count is too large".to_owned())
+        );
+        assert_eq!(error("Cxx0"), (1, "unknown condition 'xx'".to_owned()));
+        assert_eq!(error("Zy0"), (1, "unknown zero condition 'y'".to_owned()));
+    }
+}
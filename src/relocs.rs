@@ -0,0 +1,395 @@
+use crate::memory::{AddressSpace, MemoryError};
+use color_eyre::eyre::{bail, ContextCompat, Result};
+use object::elf::{
+    R_386_GLOB_DAT, R_386_JMP_SLOT, R_386_RELATIVE, R_AARCH64_GLOB_DAT, R_AARCH64_JUMP_SLOT,
+    R_AARCH64_RELATIVE, R_ARM_GLOB_DAT, R_ARM_JUMP_SLOT, R_ARM_RELATIVE, R_X86_64_GLOB_DAT,
+    R_X86_64_JUMP_SLOT, R_X86_64_RELATIVE,
+};
+use object::{
+    Architecture, Object, ObjectSymbol, ObjectSymbolTable, RelocationKind, RelocationTarget,
+};
+use std::collections::HashMap;
+use std::fmt;
+
+/// A dynamic relocation of a pointer in the shared object
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Reloc<'data> {
+    /// `R_AARCH64_RELATIVE` and its equivalents: the load base plus the
+    /// addend
+    Relative { addend: i64 },
+    /// `R_AARCH64_ABS64` and its equivalents without a symbol: just the
+    /// addend
+    Absolute { addend: i64 },
+    /// An absolute relocation, `GLOB_DAT` or `JUMP_SLOT`: the address of a
+    /// dynamic symbol plus the addend
+    Symbol { name: &'data str, addend: i64 },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PointerError {
+    Memory(MemoryError),
+    /// The pointer is relocated against a symbol the shared object does not
+    /// define, so its value is only known at runtime
+    UndefinedSymbol {
+        addr: u64,
+        name: String,
+    },
+}
+
+impl fmt::Display for PointerError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PointerError::Memory(err) => err.fmt(f),
+            PointerError::UndefinedSymbol { addr, name } => write!(
+                f,
+                "pointer at {:#x} refers to undefined symbol '{}'",
+                addr, name
+            ),
+        }
+    }
+}
+
+impl std::error::Error for PointerError {}
+
+impl From<MemoryError> for PointerError {
+    fn from(err: MemoryError) -> Self {
+        PointerError::Memory(err)
+    }
+}
+
+/// The relocation types of an architecture that store pointers
+struct RelocKinds {
+    relative: u32,
+    /// `GLOB_DAT` and `JUMP_SLOT`
+    symbolic: [u32; 2],
+    /// The size of a pointer in bytes, 4 or 8
+    pointer_size: u64,
+}
+
+impl RelocKinds {
+    fn new(machine: Architecture, pointer_size: u64) -> Result<Self> {
+        let (relative, symbolic) = match machine {
+            Architecture::Aarch64 => (
+                R_AARCH64_RELATIVE,
+                [R_AARCH64_GLOB_DAT, R_AARCH64_JUMP_SLOT],
+            ),
+            Architecture::Arm => (R_ARM_RELATIVE, [R_ARM_GLOB_DAT, R_ARM_JUMP_SLOT]),
+            Architecture::X86_64 => (R_X86_64_RELATIVE, [R_X86_64_GLOB_DAT, R_X86_64_JUMP_SLOT]),
+            Architecture::I386 => (R_386_RELATIVE, [R_386_GLOB_DAT, R_386_JMP_SLOT]),
+            machine => bail!("unsupported architecture {:?}", machine),
+        };
+        Ok(Self {
+            relative,
+            symbolic,
+            pointer_size,
+        })
+    }
+
+    /// The pointer a relocation stores, or `None` for relocations that do not
+    /// store a whole pointer. `size` is in bits, and `implicit` is whether
+    /// `addend` was read from the relocated slot.
+    fn parse<'data>(
+        &self,
+        kind: RelocationKind,
+        size: u8,
+        symbol: Option<&'data str>,
+        addend: i64,
+        implicit: bool,
+    ) -> Option<Reloc<'data>> {
+        let pointer_sized = size as u64 == self.pointer_size * 8;
+        Some(match (kind, symbol) {
+            (RelocationKind::Elf(kind), _) if kind == self.relative => Reloc::Relative { addend },
+            (RelocationKind::Absolute, None) if pointer_sized => Reloc::Absolute { addend },
+            (RelocationKind::Absolute, Some(name)) if pointer_sized => {
+                Reloc::Symbol { name, addend }
+            }
+            // The dynamic linker ignores whatever is stored in the slot
+            (RelocationKind::Elf(kind), Some(name)) if self.symbolic.contains(&kind) => {
+                Reloc::Symbol {
+                    name,
+                    addend: if implicit { 0 } else { addend },
+                }
+            }
+            _ => return None,
+        })
+    }
+}
+
+/// The addend stored in the slot of a `REL` relocation, which is signed in
+/// 32-bit shared objects
+fn implicit_addend(
+    memory: &AddressSpace,
+    offset: u64,
+    pointer_size: u64,
+) -> Result<i64, MemoryError> {
+    Ok(match pointer_size {
+        4 => memory.read_u32(offset)? as i32 as i64,
+        _ => memory.read_u64(offset)? as i64,
+    })
+}
+
+/// The memory of the shared object as it is after the dynamic linker has
+/// applied its relocations, assuming it is loaded at address 0.
+#[derive(Debug)]
+pub struct RelocatedMemory<'data> {
+    memory: AddressSpace<'data>,
+    /// The size of a pointer in bytes, 4 or 8
+    pointer_size: u64,
+    relocs: HashMap<u64, Reloc<'data>>,
+    symbols: HashMap<&'data str, u64>,
+}
+
+impl<'data> RelocatedMemory<'data> {
+    /// `symbols` are the addresses of the symbols defined by the shared
+    /// object, which symbol relocations are resolved against.
+    pub fn new<'file, O>(elf: &'file O, symbols: HashMap<&'data str, u64>) -> Result<Self>
+    where
+        'data: 'file,
+        O: Object<'data, 'file>,
+    {
+        let memory = AddressSpace::new(elf)?;
+        let pointer_size = if elf.is_64() { 8 } else { 4 };
+        let kinds = RelocKinds::new(elf.architecture(), pointer_size)?;
+        let dynamic_symbols = elf.dynamic_symbol_table();
+        let mut relocs = HashMap::new();
+        for (offset, reloc) in elf.dynamic_relocations().into_iter().flatten() {
+            let implicit = reloc.has_implicit_addend();
+            let addend = if implicit {
+                implicit_addend(&memory, offset, pointer_size)?
+            } else {
+                reloc.addend()
+            };
+            let symbol = match reloc.target() {
+                RelocationTarget::Symbol(index) => Some(
+                    dynamic_symbols
+                        .as_ref()
+                        .context("relocation against a symbol without a dynamic symbol table")?
+                        .symbol_by_index(index)?
+                        .name()?,
+                ),
+                _ => None,
+            };
+            if let Some(parsed) = kinds.parse(reloc.kind(), reloc.size(), symbol, addend, implicit)
+            {
+                relocs.insert(offset, parsed);
+            }
+        }
+
+        Ok(Self {
+            memory,
+            pointer_size,
+            relocs,
+            symbols,
+        })
+    }
+
+    /// Memory with the given relocations already parsed, for tests that have
+    /// no ELF file to parse them from
+    #[cfg(test)]
+    pub(crate) fn from_raw(
+        memory: AddressSpace<'data>,
+        pointer_size: u64,
+        relocs: HashMap<u64, Reloc<'data>>,
+        symbols: HashMap<&'data str, u64>,
+    ) -> Self {
+        Self {
+            memory,
+            pointer_size,
+            relocs,
+            symbols,
+        }
+    }
+
+    /// The address of a symbol defined by the shared object
+    pub fn symbol(&self, name: &str) -> Option<u64> {
+        self.symbols.get(name).copied()
+    }
+
+    /// The relocation applied to the pointer at `addr`, if there is one
+    pub fn reloc(&self, addr: u64) -> Option<Reloc<'data>> {
+        self.relocs.get(&addr).copied()
+    }
+
+    /// The size of a pointer in bytes, 4 or 8
+    pub fn pointer_size(&self) -> u64 {
+        self.pointer_size
+    }
+
+    /// Truncates the result of address arithmetic to the pointer size
+    pub fn wrap(&self, addr: u64) -> u64 {
+        match self.pointer_size {
+            4 => addr as u32 as u64,
+            _ => addr,
+        }
+    }
+
+    pub fn read(&self, addr: u64, len: usize) -> Result<&'data [u8], MemoryError> {
+        self.memory.read(addr, len)
+    }
+
+    pub fn read_u32(&self, addr: u64) -> Result<u32, MemoryError> {
+        self.memory.read_u32(addr)
+    }
+
+    pub fn is_executable(&self, addr: u64) -> bool {
+        self.memory.is_executable(addr)
+    }
+
+    /// Reads the pointer stored at `addr`, applying its dynamic relocation.
+    pub fn read_pointer(&self, addr: u64) -> Result<u64, PointerError> {
+        match self.reloc(addr) {
+            Some(Reloc::Relative { addend }) | Some(Reloc::Absolute { addend }) => {
+                Ok(self.wrap(addend as u64))
+            }
+            Some(Reloc::Symbol { name, addend }) => match self.symbol(name) {
+                Some(symbol) => Ok(self.wrap(symbol.wrapping_add(addend as u64))),
+                None => Err(PointerError::UndefinedSymbol {
+                    addr,
+                    name: name.to_owned(),
+                }),
+            },
+            None if self.pointer_size == 4 => Ok(self.memory.read_u32(addr)? as u64),
+            None => Ok(self.memory.read_u64(addr)?),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_pointer_relocations() {
+        for (machine, pointer_size, relative, glob_dat, jump_slot) in [
+            (
+                Architecture::Aarch64,
+                8,
+                R_AARCH64_RELATIVE,
+                R_AARCH64_GLOB_DAT,
+                R_AARCH64_JUMP_SLOT,
+            ),
+            (
+                Architecture::Arm,
+                4,
+                R_ARM_RELATIVE,
+                R_ARM_GLOB_DAT,
+                R_ARM_JUMP_SLOT,
+            ),
+            (
+                Architecture::X86_64,
+                8,
+                R_X86_64_RELATIVE,
+                R_X86_64_GLOB_DAT,
+                R_X86_64_JUMP_SLOT,
+            ),
+            (
+                Architecture::I386,
+                4,
+                R_386_RELATIVE,
+                R_386_GLOB_DAT,
+                R_386_JMP_SLOT,
+            ),
+        ] {
+            let kinds = RelocKinds::new(machine, pointer_size).unwrap();
+            let bits = pointer_size as u8 * 8;
+            let parse =
+                |kind, size, symbol, implicit| kinds.parse(kind, size, symbol, 16, implicit);
+            assert_eq!(
+                parse(RelocationKind::Elf(relative), bits, None, false),
+                Some(Reloc::Relative { addend: 16 })
+            );
+            assert_eq!(
+                parse(RelocationKind::Absolute, bits, None, false),
+                Some(Reloc::Absolute { addend: 16 })
+            );
+            assert_eq!(
+                parse(RelocationKind::Absolute, bits, Some("f"), true),
+                Some(Reloc::Symbol {
+                    name: "f",
+                    addend: 16
+                })
+            );
+            for kind in [glob_dat, jump_slot] {
+                assert_eq!(
+                    parse(RelocationKind::Elf(kind), bits, Some("f"), false),
+                    Some(Reloc::Symbol {
+                        name: "f",
+                        addend: 16
+                    })
+                );
+                assert_eq!(
+                    parse(RelocationKind::Elf(kind), bits, Some("f"), true),
+                    Some(Reloc::Symbol {
+                        name: "f",
+                        addend: 0
+                    })
+                );
+            }
+            // Not a whole pointer
+            assert_eq!(parse(RelocationKind::Absolute, 16, None, false), None);
+            assert_eq!(parse(RelocationKind::Relative, bits, None, false), None);
+        }
+        assert!(RelocKinds::new(Architecture::Riscv64, 8).is_err());
+    }
+
+    #[test]
+    fn sign_extends_32_bit_implicit_addends() {
+        let data = 0xffff_fff0_ffff_fff0u64.to_le_bytes();
+        let memory = AddressSpace::from_raw(&[(0x1000, &data)]);
+        assert_eq!(implicit_addend(&memory, 0x1000, 4), Ok(-16));
+        assert_eq!(
+            implicit_addend(&memory, 0x1000, 8),
+            Ok(0xffff_fff0_ffff_fff0u64 as i64)
+        );
+        assert!(implicit_addend(&memory, 0x1004, 8).is_err());
+    }
+
+    #[test]
+    fn reads_relocated_pointers() {
+        let data = 0x1122_3344_5566_7788u64.to_le_bytes();
+        let relocs = HashMap::from([
+            (0x1000, Reloc::Relative { addend: 0x40 }),
+            (0x1004, Reloc::Absolute { addend: -1 }),
+            (
+                0x1008,
+                Reloc::Symbol {
+                    name: "defined",
+                    addend: 8,
+                },
+            ),
+            (
+                0x100c,
+                Reloc::Symbol {
+                    name: "undefined",
+                    addend: 0,
+                },
+            ),
+        ]);
+        let symbols = HashMap::from([("defined", 0xffff_fffc)]);
+        let memory = |pointer_size| {
+            RelocatedMemory::from_raw(
+                AddressSpace::from_raw(&[(0x1000, &data), (0x2000, &data)]),
+                pointer_size,
+                relocs.clone(),
+                symbols.clone(),
+            )
+        };
+        let (memory_32, memory_64) = (memory(4), memory(8));
+        assert_eq!(memory_32.read_pointer(0x1000), Ok(0x40));
+        assert_eq!(memory_32.read_pointer(0x1004), Ok(0xffff_ffff));
+        assert_eq!(memory_64.read_pointer(0x1004), Ok(u64::MAX));
+        // Symbol plus addend, wrapped to the pointer size
+        assert_eq!(memory_32.read_pointer(0x1008), Ok(4));
+        assert_eq!(memory_64.read_pointer(0x1008), Ok(0x1_0000_0004));
+        assert_eq!(
+            memory_64.read_pointer(0x100c),
+            Err(PointerError::UndefinedSymbol {
+                addr: 0x100c,
+                name: "undefined".to_owned()
+            })
+        );
+        // Without a relocation the pointer is read as stored
+        assert_eq!(memory_32.read_pointer(0x2000), Ok(0x5566_7788));
+        assert_eq!(memory_64.read_pointer(0x2000), Ok(0x1122_3344_5566_7788));
+    }
+}
@@ -0,0 +1,1173 @@
+use crate::error::{trace_bail, FailureKind, TraceError, TraceResult};
+use crate::start::Start;
+use crate::XRefData;
+use color_eyre::eyre::Result;
+use il2cpp_binary::{
+    CodeRegistration, Il2CppGenericMethodFunctionsDefinitions, MetadataRegistration, TypeData,
+};
+use il2cpp_metadata_raw::{Il2CppMethodDefinition, Metadata};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+
+/// The most methods to suggest for a method that is not in the metadata
+const MAX_SUGGESTIONS: usize = 5;
+/// The largest edit distance from the requested name to suggest a name at
+const MAX_SUGGESTION_DISTANCE: usize = 8;
+
+fn offset_len(offset: u32, len: u32) -> std::ops::Range<usize> {
+    if (offset as i32) < 0 {
+        return 0..0;
+    }
+    offset as usize..offset as usize + len as usize
+}
+
+/// Finds the method and invoker addresses of every `il2cpp:` and `invoker:`
+/// trace start in `xref_data`. Methods without compiled code start from
+/// `null_method_fallback` instead.
+pub fn find_roots<'x>(
+    metadata: &Metadata,
+    metadata_registration: &MetadataRegistration,
+    code_registration: &CodeRegistration,
+    null_method_fallback: NullMethodFallback,
+    xref_data: &'x XRefData,
+) -> Result<Roots<'x>> {
+    // Requested methods, grouped by method name to keep the search cheap
+    let mut required_roots: HashMap<&str, Vec<MethodRef>> = HashMap::new();
+    for trace in &xref_data.traces {
+        // Malformed starts are reported when their trace runs
+        if let Ok(Start::Method(method) | Start::Invoker(method)) = Start::parse(&trace.start) {
+            let requests = required_roots.entry(method.method).or_default();
+            if !requests.contains(&method) {
+                requests.push(method);
+            }
+        }
+    }
+
+    let types = TypeNames::new(metadata, metadata_registration);
+    let mut matches: HashMap<MethodRef, Vec<MethodMatch>> = HashMap::new();
+    for image in &metadata.images {
+        let image_name = metadata.get_str(image.name_index)?;
+        let type_defs_range = offset_len(image.type_start, image.type_count);
+        for type_def_idx in type_defs_range {
+            let type_def = &metadata.type_definitions[type_def_idx];
+            let method_range = offset_len(type_def.method_start, type_def.method_count as u32);
+            for method_idx in method_range {
+                let method = &metadata.methods[method_idx];
+                let method_name = metadata.get_str(method.name_index)?;
+                let requests = match required_roots.get(method_name) {
+                    Some(requests) => requests,
+                    None => continue,
+                };
+                for request in requests {
+                    if types.matches(request, image_name, type_def_idx, method)? {
+                        matches
+                            .entry(request.clone())
+                            .or_default()
+                            .push(MethodMatch {
+                                image_name,
+                                type_name: types.type_def_name(type_def_idx)?,
+                                method_name,
+                                param_types: types.param_types(method)?,
+                                token: method.token,
+                                method_idx,
+                            });
+                    }
+                }
+            }
+        }
+    }
+
+    // Instantiations of generic methods and of methods on generic types,
+    // by the method definition they instantiate
+    let mut generic_methods: HashMap<usize, Vec<&Il2CppGenericMethodFunctionsDefinitions>> =
+        HashMap::new();
+    for generic_method in &metadata_registration.generic_method_table {
+        let spec = metadata_registration
+            .method_specs
+            .get(generic_method.generic_method_index as usize);
+        if let Some(spec) = spec {
+            generic_methods
+                .entry(spec.method_definition_index as usize)
+                .or_default()
+                .push(generic_method);
+        }
+    }
+
+    let mut roots = HashMap::new();
+    let mut null_methods = Vec::new();
+    for (request, found) in matches {
+        let root = match &found[..] {
+            [method] => {
+                let instantiations = generic_methods
+                    .get(&method.method_idx)
+                    .map_or(&[][..], Vec::as_slice);
+                let instantiated = request.class_args.is_some() || request.method_args.is_some();
+                match Root::get(method.token, method.image_name, code_registration) {
+                    // Generic code only exists for each instantiation
+                    _ if instantiated => {
+                        types.generic_root(&request, instantiations, code_registration)?
+                    }
+                    Ok(root) if root.method_addr == 0 && !instantiations.is_empty() => {
+                        types.generic_root(&request, instantiations, code_registration)?
+                    }
+                    Ok(root) if root.method_addr == 0 => {
+                        let stub = || {
+                            unresolved_virtual_call_stub(
+                                metadata,
+                                metadata_registration,
+                                code_registration,
+                                &metadata.methods[method.method_idx],
+                            )
+                        };
+                        let (root, null_method) =
+                            null_method_root(&request, root, null_method_fallback, stub);
+                        null_methods.push(null_method);
+                        Ok(root)
+                    }
+                    root => root,
+                }
+            }
+            _ => Err(TraceError::new(
+                FailureKind::AmbiguousRoot,
+                format!(
+                    "'{}' matches {} methods: {}",
+                    request,
+                    found.len(),
+                    found
+                        .iter()
+                        .map(|method| method.to_string())
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                ),
+            )),
+        };
+        roots.insert(request, root);
+    }
+
+    let mut unresolved = Vec::new();
+    let mut classes: Option<HashMap<String, Vec<usize>>> = None;
+    for request in required_roots.into_values().flatten() {
+        if roots.contains_key(&request) {
+            continue;
+        }
+        // Only index every class once something is missing
+        let classes = match &mut classes {
+            Some(classes) => classes,
+            None => {
+                let mut index: HashMap<String, Vec<usize>> = HashMap::new();
+                for idx in 0..metadata.type_definitions.len() {
+                    index.entry(types.class_ref(idx)?).or_default().push(idx);
+                }
+                classes.insert(index)
+            }
+        };
+        let root = UnresolvedRoot {
+            method: request.to_string(),
+            suggestions: types.suggestions(&request, classes)?,
+        };
+        roots.insert(
+            request,
+            Err(TraceError::new(FailureKind::MissingRoot, root.to_string())),
+        );
+        unresolved.push(root);
+    }
+    // Requests are grouped by a hash map, so sort them for stable reports
+    unresolved.sort_by(|a, b| a.method.cmp(&b.method));
+
+    null_methods.sort_by(|a, b| a.method.cmp(&b.method));
+
+    Ok(Roots {
+        found: roots,
+        unresolved,
+        null_methods,
+        unavailable_reason: None,
+    })
+}
+
+/// Where traces from a method without compiled code start instead
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum NullMethodFallback {
+    /// Nowhere, so the traces fail with `null_method_pointer`
+    #[default]
+    None,
+    /// The method's invoker
+    Invoker,
+    /// The stub called for unresolved virtual calls with the method's
+    /// signature
+    UnresolvedVirtualCall,
+}
+
+impl FromStr for NullMethodFallback {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(NullMethodFallback::None),
+            "invoker" => Ok(NullMethodFallback::Invoker),
+            "unresolved-virtual-call" => Ok(NullMethodFallback::UnresolvedVirtualCall),
+            _ => Err(format!(
+                "unknown fallback '{}', expected 'none', 'invoker' or 'unresolved-virtual-call'",
+                s
+            )),
+        }
+    }
+}
+
+/// A requested method without compiled code, such as an abstract or
+/// stripped method
+#[derive(Serialize, Debug, Clone)]
+pub struct NullMethod {
+    pub method: String,
+    /// The fallback its traces start from instead, if one was used
+    pub fallback: Option<NullMethodFallback>,
+    /// The address its traces start from instead
+    pub fallback_addr: Option<u64>,
+}
+
+impl fmt::Display for NullMethod {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "method '{}' has no compiled code", self.method)?;
+        match (self.fallback, self.fallback_addr) {
+            (Some(NullMethodFallback::Invoker), Some(addr)) => {
+                write!(f, "; starting from its invoker at {:#x}", addr)
+            }
+            (Some(NullMethodFallback::UnresolvedVirtualCall), Some(addr)) => write!(
+                f,
+                "; starting from its unresolved virtual call stub at {:#x}",
+                addr
+            ),
+            _ => write!(f, "; its il2cpp: traces will fail"),
+        }
+    }
+}
+
+/// Applies `fallback` to the root of a method whose method pointer is null.
+/// Without a fallback the method pointer stays null, which `il2cpp:` starts
+/// fail on.
+fn null_method_root(
+    request: &MethodRef,
+    root: Root,
+    fallback: NullMethodFallback,
+    stub: impl FnOnce() -> Option<u64>,
+) -> (Root, NullMethod) {
+    let fallback_addr = match fallback {
+        NullMethodFallback::None => None,
+        NullMethodFallback::Invoker => root.invoker_addr,
+        NullMethodFallback::UnresolvedVirtualCall => stub(),
+    };
+    let null_method = NullMethod {
+        method: request.to_string(),
+        fallback: fallback_addr.map(|_| fallback),
+        fallback_addr,
+    };
+    let root = Root {
+        method_addr: fallback_addr.unwrap_or(0),
+        ..root
+    };
+    (root, null_method)
+}
+
+/// Finds the stub il2cpp calls for virtual calls to methods with the
+/// signature of `method` that have no code. Each stub handles the signature
+/// (return type followed by parameter types) in the matching range of the
+/// metadata's unresolved virtual call parameter types.
+fn unresolved_virtual_call_stub(
+    metadata: &Metadata,
+    metadata_registration: &MetadataRegistration,
+    code_registration: &CodeRegistration,
+    method: &Il2CppMethodDefinition,
+) -> Option<u64> {
+    let param_range = offset_len(method.parameter_start, method.parameter_count as u32);
+    let mut signature = vec![method.return_type];
+    signature.extend(
+        metadata
+            .parameters
+            .get(param_range)?
+            .iter()
+            .map(|param| param.type_index),
+    );
+
+    let types = &metadata_registration.types;
+    let same_type = |a: u32, b: u32| {
+        a == b
+            || match (types.get(a as usize), types.get(b as usize)) {
+                (Some(a), Some(b)) => {
+                    a.byref == b.byref
+                        && matches!(
+                            (&a.data, &b.data),
+                            (TypeData::TypeDefinitionIndex(a), TypeData::TypeDefinitionIndex(b))
+                                if a == b
+                        )
+                }
+                _ => false,
+            }
+    };
+
+    let stub_idx = metadata
+        .unresolved_virtual_call_parameter_ranges
+        .iter()
+        .position(|range| {
+            let range = offset_len(range.start, range.length);
+            metadata
+                .unresolved_virtual_call_parameter_types
+                .get(range)
+                .is_some_and(|types| {
+                    types.len() == signature.len()
+                        && types.iter().zip(&signature).all(|(&a, &b)| same_type(a, b))
+                })
+        })?;
+    code_registration
+        .unresolved_virtual_call_pointers
+        .get(stub_idx)
+        .copied()
+        .filter(|&addr| addr != 0)
+}
+
+/// A method named by an `il2cpp:` or `invoker:` trace start:
+///
+/// ```text
+/// [<assembly>]<namespace>:<class>[/<nested>...][<args>]:<method>[`<arity>][<args>][(<params>)]
+/// ```
+///
+/// Everything but the namespace, class and method name is optional, and
+/// only narrows down which methods match. `<params>` is either the number of
+/// parameters or a comma separated list of their full type names, where `*`
+/// matches any type, e.g. `[mscorlib]System:String:Concat(System.String, *)`.
+/// The `<args>` after the class and the method pick an instantiation of a
+/// generic class or method by the full names of its type arguments, e.g.
+/// ``System.Collections.Generic:List`1<System.Object>:Add``.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct MethodRef<'a> {
+    /// The assembly the method is in, with or without `.dll`
+    pub assembly: Option<&'a str>,
+    pub namespace: &'a str,
+    /// The outermost class, followed by each nested type down to the one
+    /// declaring the method
+    pub class_path: Vec<&'a str>,
+    pub method: &'a str,
+    /// The number of generic parameters of the method itself
+    pub generic_arity: Option<usize>,
+    pub params: Option<Params<'a>>,
+    /// The type arguments of the instantiation of the class
+    pub class_args: Option<Vec<&'a str>>,
+    /// The type arguments of the instantiation of the method
+    pub method_args: Option<Vec<&'a str>>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Params<'a> {
+    Count(usize),
+    Types(Vec<&'a str>),
+}
+
+impl<'a> MethodRef<'a> {
+    /// Parses the part of a start after `il2cpp:` or `invoker:`.
+    pub fn parse(spec: &'a str) -> TraceResult<Self> {
+        let invalid = || {
+            TraceError::new(
+                FailureKind::InvalidStart,
+                format!(
+                    "expected a method of the form '[<assembly>]<namespace>:<class>:<method>', got '{}'",
+                    spec
+                ),
+            )
+        };
+
+        let (assembly, rest) = match spec.strip_prefix('[') {
+            Some(rest) => {
+                let (assembly, rest) = rest.split_once(']').ok_or_else(invalid)?;
+                (Some(assembly), rest)
+            }
+            None => (None, spec),
+        };
+        let (path, params) = match rest.strip_suffix(')') {
+            Some(rest) => {
+                let (path, params) = rest.split_once('(').ok_or_else(invalid)?;
+                (path, Some(Params::parse(params)))
+            }
+            None => (rest, None),
+        };
+        let parts: Vec<&str> = path.split(':').collect();
+        let (namespace, classes, method) = match parts[..] {
+            [namespace, classes, method] => (namespace, classes, method),
+            _ => return Err(invalid()),
+        };
+        let (method, method_args) = type_args(method);
+        let (method, generic_arity) = match method.split_once('`') {
+            Some((method, arity)) => match arity.parse() {
+                Ok(arity) => (method, Some(arity)),
+                Err(_) => trace_bail!(
+                    InvalidStart,
+                    "invalid generic arity '{}' in method '{}'",
+                    arity,
+                    spec
+                ),
+            },
+            None => (method, None),
+        };
+        let (classes, class_args) = type_args(classes);
+        let class_path: Vec<&str> = classes.split('/').collect();
+        if method.is_empty() || class_path.iter().any(|class| class.is_empty()) {
+            return Err(invalid());
+        }
+
+        Ok(Self {
+            assembly,
+            namespace,
+            class_path,
+            method,
+            generic_arity,
+            params,
+            class_args,
+            method_args,
+        })
+    }
+}
+
+/// Splits the `<...>` type arguments off the end of a class or method name.
+/// Names that only contain brackets as part of the name itself, such as
+/// `<Module>`, `<Start>d__5` or `IEnumerator<T>.get_Current`, are returned
+/// whole.
+fn type_args(name: &str) -> (&str, Option<Vec<&str>>) {
+    let rest = match name.strip_suffix('>') {
+        Some(rest) => rest,
+        None => return (name, None),
+    };
+    // Find the `<` matching the final `>`
+    let mut depth = 0;
+    for (pos, c) in rest.char_indices().rev() {
+        match c {
+            '>' => depth += 1,
+            '<' if depth > 0 => depth -= 1,
+            '<' if pos > 0 => {
+                let args = split_list(&rest[pos + 1..]);
+                return (&rest[..pos], Some(args));
+            }
+            '<' => break,
+            _ => {}
+        }
+    }
+    (name, None)
+}
+
+/// Splits a list of type names on the commas that are not inside the
+/// brackets of a generic instantiation or array, such as the one in
+/// `` Dictionary`2<System.Int32, System.String> ``
+fn split_list(list: &str) -> Vec<&str> {
+    let mut items = Vec::new();
+    let (mut depth, mut start) = (0usize, 0);
+    for (pos, c) in list.char_indices() {
+        match c {
+            '<' | '[' => depth += 1,
+            '>' | ']' => depth = depth.saturating_sub(1),
+            ',' if depth == 0 => {
+                items.push(list[start..pos].trim());
+                start = pos + 1;
+            }
+            _ => {}
+        }
+    }
+    items.push(list[start..].trim());
+    items
+}
+
+/// Formats the type arguments of a generic instantiation for error messages
+fn describe_args(args: &Option<Vec<Option<String>>>) -> String {
+    match args {
+        Some(args) => {
+            let names: Vec<&str> = args
+                .iter()
+                .map(|arg| arg.as_deref().unwrap_or("?"))
+                .collect();
+            format!("<{}>", names.join(", "))
+        }
+        None => String::new(),
+    }
+}
+
+fn write_args(f: &mut fmt::Formatter, args: &Option<Vec<&str>>) -> fmt::Result {
+    match args {
+        Some(args) => write!(f, "<{}>", args.join(", ")),
+        None => Ok(()),
+    }
+}
+
+impl<'a> Params<'a> {
+    fn parse(params: &'a str) -> Self {
+        let params = params.trim();
+        if params.is_empty() {
+            return Params::Types(Vec::new());
+        }
+        match params.parse() {
+            Ok(count) => Params::Count(count),
+            Err(_) => Params::Types(split_list(params)),
+        }
+    }
+}
+
+impl<'a> fmt::Display for MethodRef<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(assembly) = self.assembly {
+            write!(f, "[{}]", assembly)?;
+        }
+        write!(f, "{}:{}", self.namespace, self.class_path.join("/"))?;
+        write_args(f, &self.class_args)?;
+        write!(f, ":{}", self.method)?;
+        if let Some(arity) = self.generic_arity {
+            write!(f, "`{}", arity)?;
+        }
+        write_args(f, &self.method_args)?;
+        match &self.params {
+            Some(Params::Count(count)) => write!(f, "({})", count),
+            Some(Params::Types(types)) => write!(f, "({})", types.join(", ")),
+            None => Ok(()),
+        }
+    }
+}
+
+/// A method in the metadata matching a [`MethodRef`]
+struct MethodMatch<'md> {
+    image_name: &'md str,
+    type_name: String,
+    method_name: &'md str,
+    param_types: Vec<Option<String>>,
+    token: u32,
+    /// Index of the method definition in the metadata
+    method_idx: usize,
+}
+
+impl<'md> fmt::Display for MethodMatch<'md> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let params: Vec<&str> = self
+            .param_types
+            .iter()
+            .map(|ty| ty.as_deref().unwrap_or("?"))
+            .collect();
+        write!(
+            f,
+            "[{}]{}::{}({})",
+            self.image_name,
+            self.type_name,
+            self.method_name,
+            params.join(", ")
+        )
+    }
+}
+
+/// Names of type definitions and method parameter types, used to match
+/// methods against a [`MethodRef`].
+struct TypeNames<'a, 'md> {
+    metadata: &'a Metadata<'md>,
+    metadata_registration: &'a MetadataRegistration,
+    /// The type definition each nested type is declared in
+    declaring_types: HashMap<usize, usize>,
+}
+
+impl<'a, 'md> TypeNames<'a, 'md> {
+    fn new(metadata: &'a Metadata<'md>, metadata_registration: &'a MetadataRegistration) -> Self {
+        let mut declaring_types = HashMap::new();
+        for (idx, type_def) in metadata.type_definitions.iter().enumerate() {
+            let nested_range = offset_len(
+                type_def.nested_types_start,
+                type_def.nested_type_count as u32,
+            );
+            for &nested in metadata.nested_types.get(nested_range).unwrap_or_default() {
+                declaring_types.insert(nested as usize, idx);
+            }
+        }
+        Self {
+            metadata,
+            metadata_registration,
+            declaring_types,
+        }
+    }
+
+    /// The type definition at `idx` followed by the types it is nested in,
+    /// innermost first
+    fn nesting(&self, idx: usize) -> impl Iterator<Item = usize> + '_ {
+        std::iter::successors(Some(idx), |idx| self.declaring_types.get(idx).copied())
+    }
+
+    /// The names of the outermost type `idx` is nested in and of each type
+    /// nested in it down to `idx`
+    fn class_path(&self, idx: usize) -> Result<Vec<&'md str>> {
+        let mut names = Vec::new();
+        for idx in self.nesting(idx) {
+            names.push(
+                self.metadata
+                    .get_str(self.metadata.type_definitions[idx].name_index)?,
+            );
+        }
+        names.reverse();
+        Ok(names)
+    }
+
+    /// The class of a type definition as it is written in a [`MethodRef`],
+    /// such as `System.Collections.Generic:Dictionary`2/Entry`
+    fn class_ref(&self, idx: usize) -> Result<String> {
+        Ok(format!(
+            "{}:{}",
+            self.namespace(idx)?,
+            self.class_path(idx)?.join("/")
+        ))
+    }
+
+    /// Suggests methods with names close to those in `request`, which is
+    /// not in the metadata. If its class exists, these are the methods of
+    /// the class closest to the requested method, otherwise the classes
+    /// closest to the requested class.
+    fn suggestions(
+        &self,
+        request: &MethodRef,
+        classes: &HashMap<String, Vec<usize>>,
+    ) -> Result<Vec<String>> {
+        let class = format!("{}:{}", request.namespace, request.class_path.join("/"));
+        let mut scored: Vec<(usize, String)> = Vec::new();
+        match classes.get(&class) {
+            Some(type_defs) => {
+                for &type_def_idx in type_defs {
+                    let type_def = &self.metadata.type_definitions[type_def_idx];
+                    let method_range =
+                        offset_len(type_def.method_start, type_def.method_count as u32);
+                    for method in self.metadata.methods.get(method_range).unwrap_or_default() {
+                        let name = self.metadata.get_str(method.name_index)?;
+                        scored.push((
+                            strsim::levenshtein(request.method, name),
+                            format!("{}:{}({})", class, name, method.parameter_count),
+                        ));
+                    }
+                }
+            }
+            None => {
+                for candidate in classes.keys() {
+                    scored.push((
+                        strsim::levenshtein(&class, candidate),
+                        format!("{}:{}", candidate, request.method),
+                    ));
+                }
+            }
+        }
+
+        scored.retain(|(distance, _)| *distance <= MAX_SUGGESTION_DISTANCE);
+        scored.sort();
+        scored.dedup();
+        Ok(scored
+            .into_iter()
+            .take(MAX_SUGGESTIONS)
+            .map(|(_, suggestion)| suggestion)
+            .collect())
+    }
+
+    /// The namespace of the outermost type `idx` is nested in
+    fn namespace(&self, idx: usize) -> Result<&'md str> {
+        let outermost = self.nesting(idx).last().unwrap_or(idx);
+        self.metadata
+            .get_str(self.metadata.type_definitions[outermost].namespace_index)
+    }
+
+    /// The full name of a type definition, such as `System.String` or
+    /// `System.Collections.Generic.Dictionary`2/Entry`
+    fn type_def_name(&self, idx: usize) -> Result<String> {
+        let names = self.class_path(idx)?;
+        let namespace = self.namespace(idx)?;
+        if namespace.is_empty() {
+            Ok(names.join("/"))
+        } else {
+            Ok(format!("{}.{}", namespace, names.join("/")))
+        }
+    }
+
+    /// The full names of the parameter types of `method`. Types that are
+    /// not plain type definitions, such as arrays, generic instances and
+    /// generic parameters, have no name.
+    fn param_types(&self, method: &Il2CppMethodDefinition) -> Result<Vec<Option<String>>> {
+        let param_range = offset_len(method.parameter_start, method.parameter_count as u32);
+        let mut types = Vec::with_capacity(param_range.len());
+        for param in self
+            .metadata
+            .parameters
+            .get(param_range)
+            .unwrap_or_default()
+        {
+            types.push(self.type_name(param.type_index as usize)?);
+        }
+        Ok(types)
+    }
+
+    /// The full name of the type at `idx` in the metadata registration's
+    /// types, if it is a plain type definition
+    fn type_name(&self, idx: usize) -> Result<Option<String>> {
+        match self.metadata_registration.types.get(idx).map(|ty| &ty.data) {
+            Some(&TypeData::TypeDefinitionIndex(idx)) => {
+                Ok(Some(self.type_def_name(idx as usize)?))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// The names of the type arguments of a generic instantiation, given as
+    /// an index into the metadata registration's generic insts
+    fn generic_inst_names(&self, idx: u32) -> Result<Option<Vec<Option<String>>>> {
+        let inst = match self.metadata_registration.generic_insts.get(idx as usize) {
+            Some(inst) => inst,
+            None => return Ok(None),
+        };
+        let mut names = Vec::with_capacity(inst.types.len());
+        for &ty in &inst.types {
+            names.push(self.type_name(ty)?);
+        }
+        Ok(Some(names))
+    }
+
+    /// Resolves a method without code of its own through its generic
+    /// instantiations, keeping those with the type arguments `request`
+    /// asks for. Instantiations that share their code count as one.
+    fn generic_root(
+        &self,
+        request: &MethodRef,
+        instantiations: &[&Il2CppGenericMethodFunctionsDefinitions],
+        code_registration: &CodeRegistration,
+    ) -> Result<TraceResult<Root>> {
+        let args_match = |expected: &Option<Vec<&str>>, actual: &Option<Vec<Option<String>>>| {
+            let expected = match expected {
+                Some(expected) => expected,
+                None => return true,
+            };
+            let actual = actual.as_deref().unwrap_or_default();
+            expected.len() == actual.len()
+                && expected.iter().zip(actual).all(|(&expected, actual)| {
+                    expected == "*" || actual.as_deref() == Some(expected)
+                })
+        };
+
+        // (code, invoker, type arguments) of each matching instantiation
+        let mut found: Vec<(u64, Option<u64>, String)> = Vec::new();
+        for instantiation in instantiations {
+            let spec = match self
+                .metadata_registration
+                .method_specs
+                .get(instantiation.generic_method_index as usize)
+            {
+                Some(spec) => spec,
+                None => continue,
+            };
+            let class_args = self.generic_inst_names(spec.class_index_index)?;
+            let method_args = self.generic_inst_names(spec.method_index_index)?;
+            if !args_match(&request.class_args, &class_args)
+                || !args_match(&request.method_args, &method_args)
+            {
+                continue;
+            }
+
+            let indices = &instantiation.indices;
+            let method_addr = match code_registration
+                .generic_method_pointers
+                .get(indices.method_index as usize)
+            {
+                Some(&addr) if addr != 0 => addr,
+                _ => continue,
+            };
+            if found.iter().any(|&(addr, ..)| addr == method_addr) {
+                continue;
+            }
+            let invoker_addr = code_registration
+                .invoker_pointers
+                .get(indices.invoker_index as usize)
+                .copied();
+            let args = format!(
+                "{}:{}{}",
+                describe_args(&class_args),
+                request.method,
+                describe_args(&method_args)
+            );
+            found.push((method_addr, invoker_addr, args));
+        }
+
+        Ok(match &found[..] {
+            &[(method_addr, invoker_addr, _)] => Ok(Root {
+                method_addr,
+                invoker_addr,
+            }),
+            [] if instantiations.is_empty() => Err(TraceError::new(
+                FailureKind::MissingRoot,
+                format!(
+                    "'{}' has no code of its own and no generic instantiations",
+                    request
+                ),
+            )),
+            [] => Err(TraceError::new(
+                FailureKind::MissingRoot,
+                format!(
+                    "none of the {} instantiations of '{}' match",
+                    instantiations.len(),
+                    request
+                ),
+            )),
+            _ => Err(TraceError::new(
+                FailureKind::AmbiguousRoot,
+                format!(
+                    "'{}' has {} instantiations with different code: {}",
+                    request,
+                    found.len(),
+                    found
+                        .iter()
+                        .map(|(_, _, args)| args.as_str())
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                ),
+            )),
+        })
+    }
+
+    fn matches(
+        &self,
+        request: &MethodRef,
+        image_name: &str,
+        type_def_idx: usize,
+        method: &Il2CppMethodDefinition,
+    ) -> Result<bool> {
+        if let Some(assembly) = request.assembly {
+            if image_name != assembly && image_name.strip_suffix(".dll") != Some(assembly) {
+                return Ok(false);
+            }
+        }
+
+        // Most types are ruled out by their own name
+        let name = self.metadata.type_definitions[type_def_idx].name_index;
+        if request.class_path.last() != Some(&self.metadata.get_str(name)?) {
+            return Ok(false);
+        }
+        let class_path = self.class_path(type_def_idx)?;
+        if !class_matches(request, self.namespace(type_def_idx)?, &class_path) {
+            return Ok(false);
+        }
+
+        if let Some(arity) = request.generic_arity {
+            let method_arity = self
+                .metadata
+                .generic_containers
+                .get(method.generic_container_index as usize)
+                .map_or(0, |container| container.type_argc as usize);
+            if method_arity != arity {
+                return Ok(false);
+            }
+        }
+
+        match &request.params {
+            Some(Params::Count(count)) => Ok(method.parameter_count as usize == *count),
+            Some(Params::Types(types)) => {
+                let param_types = self.param_types(method)?;
+                Ok(param_types.len() == types.len()
+                    && types.iter().zip(&param_types).all(|(&expected, actual)| {
+                        expected == "*" || actual.as_deref() == Some(expected)
+                    }))
+            }
+            None => Ok(true),
+        }
+    }
+}
+
+/// Whether the type in `namespace` with `class_path` is the class `request`
+/// is in. Nested types may also be requested by their own name with an empty
+/// namespace, which is all the metadata gives them, as in
+/// `:<>c:<Awake>b__3_0`.
+fn class_matches(request: &MethodRef, namespace: &str, class_path: &[&str]) -> bool {
+    if request.namespace == namespace && request.class_path == class_path {
+        return true;
+    }
+    request.namespace.is_empty()
+        && request.class_path.len() == 1
+        && class_path.len() > 1
+        && class_path.last() == request.class_path.last()
+}
+
+/// The il2cpp roots requested by the traces of some xref data
+#[derive(Debug, Default)]
+pub struct Roots<'a> {
+    /// Roots keyed by the method they were requested with. Methods that
+    /// could not be found or resolved to an address, or that matched several
+    /// methods, hold the error for their traces.
+    pub found: HashMap<MethodRef<'a>, TraceResult<Root>>,
+    /// Requested methods that are not in the metadata at all
+    pub unresolved: Vec<UnresolvedRoot>,
+    /// Requested methods that have no compiled code
+    pub null_methods: Vec<NullMethod>,
+    /// Why none of the requested methods could be looked up, if the
+    /// metadata cannot be used with the shared object
+    pub unavailable_reason: Option<String>,
+}
+
+impl<'a> Roots<'a> {
+    /// The roots of a shared object whose il2cpp registrations could not be
+    /// found, where every requested method fails with `reason`.
+    pub fn unavailable(xref_data: &'a XRefData, reason: &str) -> Self {
+        let mut found = HashMap::new();
+        for trace in &xref_data.traces {
+            if let Ok(Start::Method(method) | Start::Invoker(method)) = Start::parse(&trace.start) {
+                found.insert(
+                    method,
+                    Err(TraceError::new(FailureKind::MissingRoot, reason)),
+                );
+            }
+        }
+        Self {
+            unavailable_reason: (!found.is_empty()).then(|| reason.to_owned()),
+            found,
+            ..Self::default()
+        }
+    }
+}
+
+/// A method requested by a trace start that is not in the metadata
+#[derive(Serialize, Debug, Clone)]
+pub struct UnresolvedRoot {
+    pub method: String,
+    /// Methods with similar names that are in the metadata, closest first
+    pub suggestions: Vec<String>,
+}
+
+impl fmt::Display for UnresolvedRoot {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "could not find method '{}' in metadata", self.method)?;
+        if !self.suggestions.is_empty() {
+            write!(f, "; did you mean '{}'?", self.suggestions.join("', '"))?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub struct Root {
+    pub method_addr: u64,
+    pub invoker_addr: Option<u64>,
+}
+
+impl Root {
+    pub fn get(
+        token: u32,
+        image_name: &str,
+        code_registration: &CodeRegistration,
+    ) -> TraceResult<Self> {
+        let rid = 0x00FFFFFF & token;
+        let module = match code_registration
+            .code_gen_modules
+            .iter()
+            .find(|module| module.name == image_name)
+        {
+            Some(module) => module,
+            None => trace_bail!(MissingRoot, "could not find module '{}'", image_name),
+        };
+
+        let idx = (rid as usize).wrapping_sub(1);
+        let (method_addr, invoker_idx) = match (
+            module.method_pointers.get(idx),
+            module.invoker_indices.get(idx),
+        ) {
+            (Some(&method_addr), Some(&invoker_idx)) => (method_addr, invoker_idx),
+            _ => trace_bail!(
+                MissingRoot,
+                "method token {:#x} is out of range for module '{}'",
+                token,
+                image_name
+            ),
+        };
+        let invoker_addr = if invoker_idx == u32::MAX {
+            None
+        } else {
+            match code_registration.invoker_pointers.get(invoker_idx as usize) {
+                Some(&addr) => Some(addr),
+                None => trace_bail!(MissingRoot, "invoker index {} is out of range", invoker_idx),
+            }
+        };
+
+        Ok(Self {
+            method_addr,
+            invoker_addr,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_method_refs() {
+        let method = MethodRef::parse("UnityEngine:Object:Destroy").unwrap();
+        assert_eq!(method.assembly, None);
+        assert_eq!(method.namespace, "UnityEngine");
+        assert_eq!(method.class_path, ["Object"]);
+        assert_eq!(method.method, "Destroy");
+        assert_eq!(method.generic_arity, None);
+        assert_eq!(method.params, None);
+
+        let method = MethodRef::parse("[mscorlib]System:String:Concat(System.String, *)").unwrap();
+        assert_eq!(method.assembly, Some("mscorlib"));
+        assert_eq!(
+            method.params,
+            Some(Params::Types(vec!["System.String", "*"]))
+        );
+
+        let method = MethodRef::parse(":Outer/Inner:Get`1(2)").unwrap();
+        assert_eq!(method.namespace, "");
+        assert_eq!(method.class_path, ["Outer", "Inner"]);
+        assert_eq!(method.method, "Get");
+        assert_eq!(method.generic_arity, Some(1));
+        assert_eq!(method.params, Some(Params::Count(2)));
+
+        let method = MethodRef::parse("System:Object:.ctor()").unwrap();
+        assert_eq!(method.method, ".ctor");
+        assert_eq!(method.params, Some(Params::Types(Vec::new())));
+    }
+
+    #[test]
+    fn parses_type_arguments() {
+        let method =
+            MethodRef::parse("System.Collections.Generic:List`1<System.Object>:Add").unwrap();
+        assert_eq!(method.class_path, ["List`1"]);
+        assert_eq!(method.class_args, Some(vec!["System.Object"]));
+        assert_eq!(method.method, "Add");
+
+        let method = MethodRef::parse("System:Array:Empty`1<System.Int32, System.String>").unwrap();
+        assert_eq!(method.method, "Empty");
+        assert_eq!(method.generic_arity, Some(1));
+        assert_eq!(
+            method.method_args,
+            Some(vec!["System.Int32", "System.String"])
+        );
+    }
+
+    #[test]
+    fn splits_nested_type_arguments() {
+        let method = MethodRef::parse(
+            "System.Collections.Generic:Dictionary`2<System.Collections.Generic.KeyValuePair`2<A, B>, C>:Add(System.Collections.Generic.KeyValuePair`2<A, B>, System.Int32[,])",
+        )
+        .unwrap();
+        assert_eq!(method.class_path, ["Dictionary`2"]);
+        assert_eq!(
+            method.class_args,
+            Some(vec!["System.Collections.Generic.KeyValuePair`2<A, B>", "C"])
+        );
+        assert_eq!(method.method, "Add");
+        assert_eq!(
+            method.params,
+            Some(Params::Types(vec![
+                "System.Collections.Generic.KeyValuePair`2<A, B>",
+                "System.Int32[,]"
+            ]))
+        );
+    }
+
+    #[test]
+    fn keeps_brackets_that_are_part_of_names() {
+        for (spec, class_path, method_name) in [
+            (":<Module>:.cctor", vec!["<Module>"], ".cctor"),
+            (
+                ":<PrivateImplementationDetails>:ComputeStringHash",
+                vec!["<PrivateImplementationDetails>"],
+                "ComputeStringHash",
+            ),
+            (
+                "Game:Player/<>c:<Awake>b__3_0",
+                vec!["Player", "<>c"],
+                "<Awake>b__3_0",
+            ),
+            (
+                "Game:Player/<Start>d__5:MoveNext",
+                vec!["Player", "<Start>d__5"],
+                "MoveNext",
+            ),
+            (
+                "Game:Enumerator:System.Collections.Generic.IEnumerator<T>.get_Current",
+                vec!["Enumerator"],
+                "System.Collections.Generic.IEnumerator<T>.get_Current",
+            ),
+        ] {
+            let method = MethodRef::parse(spec).unwrap();
+            assert_eq!(method.class_path, class_path, "{}", spec);
+            assert_eq!(method.method, method_name, "{}", spec);
+            assert_eq!(method.class_args, None, "{}", spec);
+            assert_eq!(method.method_args, None, "{}", spec);
+        }
+
+        let method = MethodRef::parse("Game:Player/<>c:<Awake>b__3_0<System.Int32>").unwrap();
+        assert_eq!(method.method, "<Awake>b__3_0");
+        assert_eq!(method.method_args, Some(vec!["System.Int32"]));
+    }
+
+    #[test]
+    fn matches_nested_classes_by_path_or_own_name() {
+        let matches = |spec, namespace, class_path: &[&str]| {
+            class_matches(&MethodRef::parse(spec).unwrap(), namespace, class_path)
+        };
+        let nested = ["Player", "<>c"];
+        assert!(matches("Game:Player/<>c:<Awake>b__3_0", "Game", &nested));
+        assert!(matches(":<>c:<Awake>b__3_0", "Game", &nested));
+        assert!(matches(
+            ":<Start>d__5:MoveNext",
+            "Game",
+            &["Player", "<Start>d__5"]
+        ));
+        assert!(!matches(":Player/<>c:<Awake>b__3_0", "Game", &nested));
+        assert!(!matches("Game:<>c:<Awake>b__3_0", "Game", &nested));
+        assert!(!matches(":Player:Awake", "Game", &nested));
+        assert!(matches("Game:Player:Awake", "Game", &["Player"]));
+        assert!(!matches(":Player:Awake", "Game", &["Player"]));
+        assert!(matches(":<Module>:.cctor", "", &["<Module>"]));
+    }
+
+    #[test]
+    fn reports_unavailable_roots_only_when_requested() {
+        let xref_data = |start: &str| XRefData {
+            traces: vec![crate::SymbolTrace {
+                symbol: "symbol".to_owned(),
+                start: start.to_owned(),
+                trace: String::new(),
+            }],
+        };
+        let requested = xref_data("il2cpp:UnityEngine:Object:Destroy");
+        let roots = Roots::unavailable(&requested, "no registrations");
+        assert_eq!(
+            roots.unavailable_reason.as_deref(),
+            Some("no registrations")
+        );
+        let method = MethodRef::parse("UnityEngine:Object:Destroy").unwrap();
+        let err = roots.found[&method].as_ref().unwrap_err();
+        assert_eq!(err.kind, FailureKind::MissingRoot);
+
+        let unrequested = xref_data("JNI_OnLoad");
+        let roots = Roots::unavailable(&unrequested, "no registrations");
+        assert_eq!(roots.unavailable_reason, None);
+    }
+
+    #[test]
+    fn method_refs_display_as_parsed() {
+        for spec in [
+            "UnityEngine:Object:Destroy",
+            "[mscorlib]System:String:Concat(System.String, *)",
+            ":Outer/Inner:Get`1(2)",
+            "System.Collections.Generic:List`1<System.Object>:Add",
+            ":<Module>:.cctor",
+        ] {
+            assert_eq!(MethodRef::parse(spec).unwrap().to_string(), spec);
+        }
+    }
+
+    #[test]
+    fn rejects_malformed_method_refs() {
+        for spec in [
+            "Object:Destroy",
+            "A:B:C:D",
+            "[mscorlib System:String:Concat",
+            "System:String:",
+            "System:Outer//Inner:Get",
+            "System:String:Concat`x",
+        ] {
+            let err = MethodRef::parse(spec).unwrap_err();
+            assert_eq!(err.kind, FailureKind::InvalidStart, "{}", spec);
+        }
+    }
+}
@@ -0,0 +1,50 @@
+//! The parts of the shared object that traces walk over.
+
+use crate::arch::{self, InstructionSet};
+use crate::functions::FunctionMap;
+use crate::insn_cache::InsnCache;
+use crate::relocs::RelocatedMemory;
+use crate::start::StartPoints;
+use color_eyre::eyre::Result;
+use il2cpp_binary::CodeRegistration;
+use object::read::elf::{ElfFile, FileHeader};
+use object::{Endianness, Object, ObjectSymbol};
+use std::collections::HashMap;
+
+/// A parsed shared object of any supported architecture, shared by every
+/// tracer created from it.
+#[derive(Debug)]
+pub struct SharedObject<'data> {
+    pub memory: RelocatedMemory<'data>,
+    pub functions: FunctionMap,
+    pub starts: StartPoints<'data>,
+    pub arch: Box<dyn InstructionSet>,
+    /// Instructions decoded by any trace so far
+    pub insns: InsnCache,
+}
+
+impl<'data> SharedObject<'data> {
+    /// `code_registration` adds the il2cpp method pointers as function entry
+    /// points and trace starts, if it could be found.
+    pub fn new<Elf: FileHeader<Endian = Endianness>>(
+        elf: &ElfFile<'data, Elf>,
+        code_registration: Option<&CodeRegistration<'data>>,
+    ) -> Result<Self> {
+        let arch = arch::for_machine(elf.architecture())?;
+
+        let mut symbols = HashMap::new();
+        for symbol in elf.dynamic_symbols() {
+            if symbol.is_definition() {
+                symbols.insert(symbol.name()?, symbol.address());
+            }
+        }
+
+        Ok(Self {
+            memory: RelocatedMemory::new(elf, symbols)?,
+            functions: FunctionMap::new(elf, code_registration, arch.as_ref())?,
+            starts: StartPoints::new(elf, code_registration)?,
+            arch,
+            insns: InsnCache::default(),
+        })
+    }
+}
@@ -0,0 +1,340 @@
+//! Where traces start.
+
+use crate::error::{trace_bail, TraceResult};
+use crate::roots::MethodRef;
+use color_eyre::eyre::Result;
+use il2cpp_binary::CodeRegistration;
+use object::elf::{DT_INIT_ARRAY, DT_INIT_ARRAYSZ};
+use object::read::elf::{Dyn, ElfFile, FileHeader, ProgramHeader};
+use object::{Endianness, Object, ObjectSection};
+use std::collections::HashMap;
+use std::ops::Range;
+
+/// A parsed trace start
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Start<'a> {
+    /// `il2cpp:<method>`: the code of an il2cpp method
+    Method(MethodRef<'a>),
+    /// `invoker:<method>`: the invoker of an il2cpp method
+    Invoker(MethodRef<'a>),
+    /// `xref:<symbol>`: another symbol traced from the same xref data
+    XRef(&'a str),
+    /// `addr:<address>`: an absolute address, in hex with `0x` or decimal
+    Addr(u64),
+    /// `section:<name>`: the start of a section, or `section:<name>[<n>]`:
+    /// the nth pointer stored in it, such as `section:.init_array[3]`
+    Section { name: &'a str, index: Option<usize> },
+    /// `init_array:<n>`: the nth pointer of the `DT_INIT_ARRAY` table
+    InitArray(usize),
+    /// `entry`: the entry point in the ELF header
+    Entry,
+    /// `codegen:<module>:<n>`: the nth method pointer of a code gen module
+    /// such as `Assembly-CSharp.dll`
+    CodeGen { module: &'a str, index: usize },
+    /// `generic_method_pointer:<n>`: the nth pointer of the code
+    /// registration's generic method pointer table
+    GenericMethodPointer(usize),
+    /// `<symbol>`: a symbol in the dynamic symbol table
+    Symbol(&'a str),
+}
+
+impl<'a> Start<'a> {
+    pub fn parse(start: &'a str) -> TraceResult<Self> {
+        if let Some(method) = start.strip_prefix("il2cpp:") {
+            Ok(Start::Method(MethodRef::parse(method)?))
+        } else if let Some(method) = start.strip_prefix("invoker:") {
+            Ok(Start::Invoker(MethodRef::parse(method)?))
+        } else if let Some(symbol) = start.strip_prefix("xref:") {
+            if symbol.is_empty() {
+                trace_bail!(InvalidStart, "expected a symbol name after 'xref:'");
+            }
+            Ok(Start::XRef(symbol))
+        } else if let Some(addr) = start.strip_prefix("addr:") {
+            Ok(Start::Addr(parse_number(start, addr)?))
+        } else if let Some(section) = start.strip_prefix("section:") {
+            let (name, index) = match section.strip_suffix(']') {
+                Some(indexed) => match indexed.split_once('[') {
+                    Some((name, index)) => (name, Some(parse_index(start, index)?)),
+                    None => trace_bail!(InvalidStart, "unmatched ']' in start '{}'", start),
+                },
+                None => (section, None),
+            };
+            if name.is_empty() {
+                trace_bail!(InvalidStart, "expected a section name after 'section:'");
+            }
+            Ok(Start::Section { name, index })
+        } else if let Some(index) = start.strip_prefix("init_array:") {
+            Ok(Start::InitArray(parse_index(start, index)?))
+        } else if start == "entry" {
+            Ok(Start::Entry)
+        } else if let Some(method) = start.strip_prefix("codegen:") {
+            // Module names contain dots but never colons
+            match method.rsplit_once(':') {
+                Some((module, index)) if !module.is_empty() => Ok(Start::CodeGen {
+                    module,
+                    index: parse_index(start, index)?,
+                }),
+                _ => trace_bail!(
+                    InvalidStart,
+                    "expected start of the form 'codegen:<module>:<index>', got '{}'",
+                    start
+                ),
+            }
+        } else if let Some(index) = start.strip_prefix("generic_method_pointer:") {
+            Ok(Start::GenericMethodPointer(parse_index(start, index)?))
+        } else {
+            Ok(Start::Symbol(start))
+        }
+    }
+
+    /// The symbol from the same xref data this start depends on, if any
+    pub fn dependency(start: &'a str) -> Option<&'a str> {
+        match Start::parse(start) {
+            Ok(Start::XRef(symbol)) => Some(symbol),
+            _ => None,
+        }
+    }
+}
+
+fn parse_number(start: &str, num: &str) -> TraceResult<u64> {
+    let parsed = match num.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16),
+        None => num.parse(),
+    };
+    match parsed {
+        Ok(num) => Ok(num),
+        Err(_) => trace_bail!(
+            InvalidStart,
+            "invalid number '{}' in start '{}'",
+            num,
+            start
+        ),
+    }
+}
+
+fn parse_index(start: &str, index: &str) -> TraceResult<usize> {
+    match index.parse() {
+        Ok(index) => Ok(index),
+        Err(_) => trace_bail!(
+            InvalidStart,
+            "invalid index '{}' in start '{}'",
+            index,
+            start
+        ),
+    }
+}
+
+/// The places in the shared object that traces can start from besides
+/// symbols and il2cpp methods.
+#[derive(Debug, Default)]
+pub struct StartPoints<'data> {
+    entry: u64,
+    /// Address range of the `DT_INIT_ARRAY` table
+    init_array: Option<Range<u64>>,
+    /// Address ranges of the sections, by name
+    sections: HashMap<String, Range<u64>>,
+    /// The tables of the code registration, if it could be found
+    code_registration: Option<CodeRegistrationStarts<'data>>,
+}
+
+#[derive(Debug, Default)]
+struct CodeRegistrationStarts<'data> {
+    /// Method pointers of each code gen module, by module name
+    code_gen_modules: HashMap<&'data str, Vec<u64>>,
+    generic_method_pointers: Vec<u64>,
+}
+
+impl<'data> StartPoints<'data> {
+    pub fn new<Elf: FileHeader<Endian = Endianness>>(
+        elf: &ElfFile<'data, Elf>,
+        code_registration: Option<&CodeRegistration<'data>>,
+    ) -> Result<Self> {
+        let mut sections = HashMap::new();
+        for section in elf.sections() {
+            let range = section.address()..section.address() + section.size();
+            sections.insert(section.name()?.to_owned(), range);
+        }
+
+        let code_registration = code_registration.map(|code_registration| CodeRegistrationStarts {
+            code_gen_modules: code_registration
+                .code_gen_modules
+                .iter()
+                .map(|module| (module.name, module.method_pointers.clone()))
+                .collect(),
+            generic_method_pointers: code_registration.generic_method_pointers.clone(),
+        });
+
+        Ok(Self {
+            entry: elf.entry(),
+            init_array: init_array(elf)?,
+            sections,
+            code_registration,
+        })
+    }
+
+    pub fn entry(&self) -> u64 {
+        self.entry
+    }
+
+    pub fn init_array(&self) -> TraceResult<Range<u64>> {
+        match &self.init_array {
+            Some(range) => Ok(range.clone()),
+            None => trace_bail!(MissingStart, "the shared object has no DT_INIT_ARRAY"),
+        }
+    }
+
+    pub fn section(&self, name: &str) -> TraceResult<Range<u64>> {
+        match self.sections.get(name) {
+            Some(range) => Ok(range.clone()),
+            None => trace_bail!(MissingStart, "the shared object has no section '{}'", name),
+        }
+    }
+
+    fn code_registration(&self) -> TraceResult<&CodeRegistrationStarts<'data>> {
+        match &self.code_registration {
+            Some(code_registration) => Ok(code_registration),
+            None => trace_bail!(
+                MissingStart,
+                "the code registration is only found in AArch64 shared objects"
+            ),
+        }
+    }
+
+    pub fn code_gen(&self, module: &str, index: usize) -> TraceResult<u64> {
+        let pointers = match self.code_registration()?.code_gen_modules.get(module) {
+            Some(pointers) => pointers,
+            None => trace_bail!(MissingStart, "could not find module '{}'", module),
+        };
+        match pointers.get(index) {
+            Some(&addr) => Ok(addr),
+            None => trace_bail!(
+                MissingStart,
+                "module '{}' only has {} method pointers",
+                module,
+                pointers.len()
+            ),
+        }
+    }
+
+    pub fn generic_method_pointer(&self, index: usize) -> TraceResult<u64> {
+        let pointers = &self.code_registration()?.generic_method_pointers;
+        match pointers.get(index) {
+            Some(&addr) => Ok(addr),
+            None => trace_bail!(
+                MissingStart,
+                "there are only {} generic method pointers",
+                pointers.len()
+            ),
+        }
+    }
+}
+
+/// Finds the `DT_INIT_ARRAY` table through the `PT_DYNAMIC` segment.
+fn init_array<Elf: FileHeader<Endian = Endianness>>(
+    elf: &ElfFile<Elf>,
+) -> Result<Option<Range<u64>>> {
+    let endian = elf.endian();
+    for segment in elf.raw_segments() {
+        let entries = match segment.dynamic(endian, elf.data())? {
+            Some(entries) => entries,
+            None => continue,
+        };
+        let mut addr = None;
+        let mut size = None;
+        for entry in entries {
+            match entry.tag32(endian) {
+                Some(DT_INIT_ARRAY) => addr = Some(entry.d_val(endian).into()),
+                Some(DT_INIT_ARRAYSZ) => size = Some(entry.d_val(endian).into()),
+                _ => {}
+            }
+        }
+        if let (Some(addr), Some(size)) = (addr, size) {
+            return Ok(Some(addr..addr + size));
+        }
+    }
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::FailureKind;
+
+    fn error(start: &str) -> String {
+        let err = Start::parse(start).unwrap_err();
+        assert_eq!(err.kind, FailureKind::InvalidStart);
+        err.message
+    }
+
+    #[test]
+    fn parses_starts() {
+        assert_eq!(Start::parse("addr:0x1f00").unwrap(), Start::Addr(0x1f00));
+        assert_eq!(Start::parse("addr:4096").unwrap(), Start::Addr(4096));
+        assert_eq!(
+            Start::parse("section:.init_array[3]").unwrap(),
+            Start::Section {
+                name: ".init_array",
+                index: Some(3)
+            }
+        );
+        assert_eq!(
+            Start::parse("section:.text").unwrap(),
+            Start::Section {
+                name: ".text",
+                index: None
+            }
+        );
+        assert_eq!(Start::parse("init_array:2").unwrap(), Start::InitArray(2));
+        assert_eq!(Start::parse("entry").unwrap(), Start::Entry);
+        assert_eq!(
+            Start::parse("codegen:Assembly-CSharp.dll:12").unwrap(),
+            Start::CodeGen {
+                module: "Assembly-CSharp.dll",
+                index: 12
+            }
+        );
+        assert_eq!(
+            Start::parse("generic_method_pointer:7").unwrap(),
+            Start::GenericMethodPointer(7)
+        );
+        assert_eq!(
+            Start::parse("xref:il2cpp_init").unwrap(),
+            Start::XRef("il2cpp_init")
+        );
+        assert_eq!(
+            Start::parse("JNI_OnLoad").unwrap(),
+            Start::Symbol("JNI_OnLoad")
+        );
+        // Only exactly `entry` is the entry point
+        assert_eq!(
+            Start::parse("entry_point").unwrap(),
+            Start::Symbol("entry_point")
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_starts() {
+        assert_eq!(
+            error("addr:0xfoo"),
+            "invalid number '0xfoo' in start 'addr:0xfoo'"
+        );
+        assert_eq!(error("addr:"), "invalid number '' in start 'addr:'");
+        assert_eq!(
+            error("section:.text]"),
+            "unmatched ']' in start 'section:.text]'"
+        );
+        assert_eq!(
+            error("section:.init_array[x]"),
+            "invalid index 'x' in start 'section:.init_array[x]'"
+        );
+        assert_eq!(
+            error("section:[1]"),
+            "expected a section name after 'section:'"
+        );
+        assert_eq!(
+            error("codegen:Assembly-CSharp.dll"),
+            "expected start of the form 'codegen:<module>:<index>', got 'codegen:Assembly-CSharp.dll'"
+        );
+        assert_eq!(error("xref:"), "expected a symbol name after 'xref:'");
+    }
+}
@@ -0,0 +1,643 @@
+use crate::arch::{Anchor, Flow, Insn, InstructionSet, Reg, Value};
+use crate::deps::{DependencyOrder, ResolvedSymbols};
+use crate::error::{trace_bail, TraceResult};
+use crate::functions::FunctionMap;
+use crate::insn_cache::InsnCache;
+use crate::ops::{TraceOp, ZeroCond};
+use crate::relocs::RelocatedMemory;
+use crate::roots::{MethodRef, NullMethod, Root, Roots, UnresolvedRoot};
+use crate::shared_object::SharedObject;
+use crate::start::{Start, StartPoints};
+use crate::validate::Validation;
+use crate::{
+    Candidate, Conflict, Output, OutputSymbol, ParsedOps, SymbolTrace, TraceFailure, XRefData,
+};
+use color_eyre::eyre::Result;
+use rayon::prelude::*;
+use std::ops::Range;
+
+/// How many instructions to look back for the instructions that produced the
+/// value of a register
+const MAX_BACKTRACK: usize = 64;
+/// How many instructions after an `ADRP` to look for the instructions using it
+const MAX_ADRP_DISTANCE: usize = 32;
+/// How many instructions an op looks at when the function it starts in is
+/// not known, so it cannot end
+const MAX_UNBOUNDED_SCAN: usize = 4096;
+
+/// Walks the instructions of the shared object according to xref traces.
+pub struct XRefTracer<'a> {
+    memory: &'a RelocatedMemory<'a>,
+    functions: &'a FunctionMap,
+    starts: &'a StartPoints<'a>,
+    arch: &'a dyn InstructionSet,
+    insns: &'a InsnCache,
+    roots: Roots<'a>,
+}
+
+impl<'a> XRefTracer<'a> {
+    pub fn new(shared_object: &'a SharedObject<'a>, roots: Roots<'a>) -> Self {
+        Self {
+            memory: &shared_object.memory,
+            functions: &shared_object.functions,
+            starts: &shared_object.starts,
+            arch: shared_object.arch.as_ref(),
+            insns: &shared_object.insns,
+            roots,
+        }
+    }
+
+    /// Methods requested by `il2cpp:` and `invoker:` starts that are not in
+    /// the metadata, which can be reported before tracing
+    pub fn unresolved_roots(&self) -> &[UnresolvedRoot] {
+        &self.roots.unresolved
+    }
+
+    /// Methods requested by `il2cpp:` and `invoker:` starts that have no
+    /// compiled code, which can be reported before tracing
+    pub fn null_methods(&self) -> &[NullMethod] {
+        &self.roots.null_methods
+    }
+
+    /// Why every `il2cpp:` and `invoker:` start fails, if the methods they
+    /// request cannot be looked up at all, which can be reported before
+    /// tracing
+    pub fn roots_unavailable(&self) -> Option<&str> {
+        self.roots.unavailable_reason.as_deref()
+    }
+
+    /// Traces every symbol in `xref_data`. Symbols that do not depend on each
+    /// other are traced in parallel on the current rayon thread pool, and the
+    /// output is the same as tracing them one at a time.
+    pub fn trace_all<'x>(&self, xref_data: &'x XRefData) -> Result<Output<'x>> {
+        Ok(self.trace_parsed(&xref_data.parse_ops()?))
+    }
+
+    /// Like [`trace_all`](Self::trace_all), with the ops of every trace
+    /// already parsed by [`XRefData::parse_ops`], so that xref data applied to
+    /// several builds is only parsed once.
+    pub fn trace_parsed<'x>(&self, ops: &ParsedOps<'x>) -> Output<'x> {
+        let xref_data = ops.xref_data();
+        // Symbols are traced after the symbols their `xref:` starts refer to
+        let order = DependencyOrder::new(xref_data);
+        let mut resolved = ResolvedSymbols::new(&order);
+        let mut traced = Vec::with_capacity(order.symbols.len());
+        for wave in &order.waves {
+            let results: Vec<_> = wave
+                .par_iter()
+                .map(|&pos| {
+                    let (symbol, indices) = &order.symbols[pos];
+                    let traces = indices.iter().map(|&idx| ops.trace(idx));
+                    let mut output = Output::default();
+                    let offset = self.trace_symbol(symbol, traces, &resolved, &mut output);
+                    (pos, offset, output)
+                })
+                .collect();
+            for (pos, offset, output) in results {
+                if let Some(offset) = offset {
+                    resolved.insert(order.symbols[pos].0, offset);
+                }
+                traced.push((pos, output));
+            }
+        }
+
+        // Keep the output in dependency order, whichever wave a symbol was in
+        traced.sort_unstable_by_key(|&(pos, _)| pos);
+        let mut output = Output {
+            unresolved_roots: self.roots.unresolved.clone(),
+            null_methods: self.roots.null_methods.clone(),
+            roots_unavailable: self.roots.unavailable_reason.clone(),
+            ..Output::default()
+        };
+        for (_, traced) in traced {
+            output.symbols.extend(traced.symbols);
+            output.failures.extend(traced.failures);
+            output.conflicts.extend(traced.conflicts);
+        }
+        output
+    }
+
+    /// Runs every alternative trace for `symbol`, adding the symbol to the
+    /// output only if all of the traces that succeed agree on its address.
+    /// Returns the address if the symbol was added.
+    fn trace_symbol<'x, 'o>(
+        &self,
+        symbol: &'x str,
+        traces: impl Iterator<Item = (&'x SymbolTrace, &'o [TraceOp])>,
+        resolved: &ResolvedSymbols,
+        output: &mut Output<'x>,
+    ) -> Option<u64> {
+        let mut found = Vec::new();
+        let mut may_be_data = false;
+        for (trace, ops) in traces {
+            match self.trace_single(trace, ops, resolved) {
+                Ok(offset) => {
+                    found.push((trace, offset));
+                    may_be_data |= matches!(ops.last(), Some(TraceOp::Adrp(..) | TraceOp::Deref));
+                }
+                Err(failure) => output.failures.push(failure),
+            }
+        }
+
+        let offset = match found.first() {
+            Some(&(_, offset)) => offset,
+            None => return None,
+        };
+        if found.iter().any(|&(_, other)| other != offset) {
+            let candidates = found
+                .into_iter()
+                .map(|(trace, offset)| Candidate {
+                    start: &trace.start,
+                    trace: &trace.trace,
+                    offset,
+                })
+                .collect();
+            output.conflicts.push(Conflict { symbol, candidates });
+            return None;
+        }
+
+        let validation = self.validate(offset);
+        output.symbols.push(OutputSymbol {
+            symbol,
+            offset,
+            confidence: validation.confidence(may_be_data),
+            validation,
+            traces: found.len(),
+        });
+        Some(offset)
+    }
+
+    /// Follows a single trace. `resolved` holds the symbols its start may
+    /// refer to with `xref:`.
+    pub fn trace_single<'x>(
+        &self,
+        trace: &'x SymbolTrace,
+        ops: &[TraceOp],
+        resolved: &ResolvedSymbols,
+    ) -> Result<u64, TraceFailure<'x>> {
+        let mut state = TraceState::default();
+        match self.walk(trace, ops, resolved, &mut state) {
+            Ok(offset) => Ok(offset),
+            Err(err) => Err(TraceFailure {
+                symbol: &trace.symbol,
+                start: &trace.start,
+                op_index: state.op_index,
+                addr: state.addr,
+                kind: err.kind,
+                message: err.message,
+            }),
+        }
+    }
+
+    fn walk(
+        &self,
+        trace: &SymbolTrace,
+        ops: &[TraceOp],
+        resolved: &ResolvedSymbols,
+        state: &mut TraceState,
+    ) -> TraceResult<u64> {
+        let start = match Start::parse(&trace.start)? {
+            Start::Method(method) => match self.root(&method)?.method_addr {
+                0 => trace_bail!(
+                    NullMethodPointer,
+                    "method '{}' has no compiled code to start from",
+                    method
+                ),
+                addr => addr,
+            },
+            Start::Invoker(method) => match self.root(&method)?.invoker_addr {
+                Some(addr) => addr,
+                None => trace_bail!(MissingRoot, "root does not have invoker pointer"),
+            },
+            Start::XRef(symbol) => resolved.get(symbol)?,
+            Start::Addr(addr) => addr,
+            Start::Section { name, index } => {
+                let section = self.starts.section(name)?;
+                match index {
+                    Some(index) => self.table_entry(section, index)?,
+                    None => section.start,
+                }
+            }
+            Start::InitArray(index) => self.table_entry(self.starts.init_array()?, index)?,
+            Start::Entry => self.starts.entry(),
+            Start::CodeGen { module, index } => self.starts.code_gen(module, index)?,
+            Start::GenericMethodPointer(index) => self.starts.generic_method_pointer(index)?,
+            Start::Symbol(symbol) => match self.memory.symbol(symbol) {
+                Some(addr) => addr,
+                None => trace_bail!(
+                    MissingSymbol,
+                    "symbol '{}' is not in the dynamic symbol table",
+                    symbol
+                ),
+            },
+        };
+
+        // The walk position lives in `state` so failures can report where they happened
+        let addr = state.addr.insert(start);
+        for (op_index, &op) in ops.iter().enumerate() {
+            state.op_index = Some(op_index);
+            let n = match op.count() {
+                Some(n) => n,
+                None => {
+                    *addr = self.memory.read_pointer(*addr)?;
+                    continue;
+                }
+            };
+            // Scans stay inside the function they start in
+            let bounds = self.functions.bounds(self.arch.code_addr(*addr));
+            let mut count = 0;
+            for scanned in 0.. {
+                match &bounds {
+                    Some(bounds) if !bounds.contains(&self.arch.code_addr(*addr)) => trace_bail!(
+                        FunctionOverrun,
+                        "reached the end of function {:#x}..{:#x} after {} of {} matching instructions",
+                        bounds.start,
+                        bounds.end,
+                        count,
+                        n + 1
+                    ),
+                    None if scanned == MAX_UNBOUNDED_SCAN => trace_bail!(
+                        FunctionOverrun,
+                        "found {} of {} matching instructions within {} instructions outside of any known function",
+                        count,
+                        n + 1,
+                        MAX_UNBOUNDED_SCAN
+                    ),
+                    _ => {}
+                }
+                let ins = self.load_ins(*addr)?;
+                if op_matches(op, &ins) {
+                    if count == n {
+                        self.follow(op, &ins, addr)?;
+                        break;
+                    }
+                    count += 1;
+                }
+                *addr = ins.next();
+            }
+        }
+
+        Ok(*addr)
+    }
+
+    /// Moves `addr` from the instruction matched by `op` to where it leads.
+    fn follow(&self, op: TraceOp, ins: &Insn, addr: &mut u64) -> TraceResult<()> {
+        *addr = match (op, ins.flow) {
+            (TraceOp::Adrp(_, user), _) => self.anchor_target(addr, ins, user)?,
+            (
+                TraceOp::Indirect(_),
+                Flow::Indirect {
+                    target: Some(target),
+                    ..
+                },
+            ) => {
+                let history = self.history(ins.addr)?;
+                self.eval(&history, history.len(), None, target)?
+            }
+            (TraceOp::Indirect(_), _) => trace_bail!(
+                UnresolvedRegister,
+                "indirect branch at {:#x} goes somewhere that cannot be tracked",
+                ins.addr
+            ),
+            (
+                _,
+                Flow::Call(to)
+                | Flow::Branch(to)
+                | Flow::CondBranch(_, to)
+                | Flow::CompareBranch(_, to)
+                | Flow::TestBranch(_, to),
+            ) => to,
+            _ => trace_bail!(OperandMismatch, "branch had wrong operands"),
+        };
+        Ok(())
+    }
+
+    /// Follows the register an `ADRP` or another [`Anchor`] at `addr` put a
+    /// partial address in to its `user`th user and returns the full address
+    /// it forms. `addr` is moved along as instructions are looked at.
+    fn anchor_target(&self, addr: &mut u64, anchor: &Insn, user: usize) -> TraceResult<u64> {
+        // A complete address is its own first user
+        let (reg, mut value, mut seen) = match (anchor.anchor, anchor.def) {
+            (Some(Anchor::Complete(target)), _) if user == 0 => return Ok(target),
+            (Some(Anchor::Complete(target)), Some((reg, Value::Const(_)))) => (reg, target, 1),
+            (Some(Anchor::Partial), Some((reg, Value::Const(value)))) => (reg, value, 0),
+            (Some(Anchor::Partial), Some((reg, Value::LoadFrom(ptr)))) => {
+                (reg, self.memory.read_pointer(ptr)?, 0)
+            }
+            _ => trace_bail!(
+                OperandMismatch,
+                "address at {:#x} is not put in a register that can be followed",
+                anchor.addr
+            ),
+        };
+        let mut next = anchor.next();
+        for _ in 0..MAX_ADRP_DISTANCE {
+            *addr = next;
+            let ins = self.load_ins(*addr)?;
+            if let Some((base, offset)) = ins.base {
+                if base == reg {
+                    if seen == user {
+                        return Ok(self.memory.wrap(value.wrapping_add(offset as u64)));
+                    }
+                    seen += 1;
+                }
+            }
+            if ins.writes.contains(reg) {
+                match ins.def {
+                    // `MOVT` fills in the high half of what `MOVW` started
+                    Some((dest, Value::InsertHigh(high))) if dest == reg => {
+                        value = insert_high(value, high)
+                    }
+                    // Adding to the register in place, as with
+                    // `add ebx, _GLOBAL_OFFSET_TABLE_` after finding the pc
+                    Some((dest, Value::Add(src, offset))) if dest == reg && src == reg => {
+                        value = self.memory.wrap(value.wrapping_add(offset as u64))
+                    }
+                    _ => trace_bail!(
+                        UnpairedAdrp,
+                        "{} from the address at {:#x} is overwritten after {} of its users",
+                        self.arch.reg_name(reg),
+                        anchor.addr,
+                        seen
+                    ),
+                }
+            }
+            next = match ins.flow {
+                Flow::Branch(to) => to,
+                Flow::Return | Flow::Indirect { call: false, .. } => trace_bail!(
+                    UnpairedAdrp,
+                    "function returns after {} users of the address at {:#x}",
+                    seen,
+                    anchor.addr
+                ),
+                _ => ins.next(),
+            };
+        }
+        trace_bail!(
+            UnpairedAdrp,
+            "found {} users of the address at {:#x} within {} instructions",
+            seen,
+            anchor.addr,
+            MAX_ADRP_DISTANCE
+        )
+    }
+
+    /// The value `reg` holds after the first `end` instructions of `history`,
+    /// found by walking back to the instructions that produced it.
+    fn value_before(&self, history: &[Insn], end: usize, reg: Reg) -> TraceResult<u64> {
+        for (idx, ins) in history[..end].iter().enumerate().rev() {
+            if !ins.writes.contains(reg) {
+                continue;
+            }
+            let value = match ins.def {
+                Some((dest, value)) if dest == reg => value,
+                _ => trace_bail!(
+                    UnresolvedRegister,
+                    "{} is set by an instruction that cannot be followed at {:#x}",
+                    self.arch.reg_name(reg),
+                    ins.addr
+                ),
+            };
+            return self.eval(history, idx, Some(reg), value);
+        }
+        trace_bail!(
+            UnresolvedRegister,
+            "could not find where {} is set within {} instructions",
+            self.arch.reg_name(reg),
+            MAX_BACKTRACK
+        )
+    }
+
+    /// Computes `value` after the first `end` instructions of `history`.
+    /// `dest` is the register it is written to, which `MOVT` keeps half of.
+    fn eval(
+        &self,
+        history: &[Insn],
+        end: usize,
+        dest: Option<Reg>,
+        value: Value,
+    ) -> TraceResult<u64> {
+        Ok(match (value, dest) {
+            (Value::Const(value), _) => value,
+            (Value::Add(src, offset), _) => {
+                let value = self.value_before(history, end, src)?;
+                self.memory.wrap(value.wrapping_add(offset as u64))
+            }
+            (Value::InsertHigh(high), Some(dest)) => {
+                insert_high(self.value_before(history, end, dest)?, high)
+            }
+            (Value::InsertHigh(_), None) => {
+                trace_bail!(UnresolvedRegister, "half a value cannot be followed")
+            }
+            (Value::Load(src, offset), _) => {
+                let value = self.value_before(history, end, src)?;
+                let ptr = self.memory.wrap(value.wrapping_add(offset as u64));
+                self.memory.read_pointer(ptr)?
+            }
+            (Value::LoadFrom(ptr), _) => self.memory.read_pointer(ptr)?,
+        })
+    }
+
+    /// Decodes up to [`MAX_BACKTRACK`] instructions leading up to `addr`,
+    /// oldest first.
+    fn history(&self, addr: u64) -> TraceResult<Vec<Insn>> {
+        let (min_len, max_len) = self.arch.insn_len();
+        let code_addr = self.arch.code_addr(addr);
+        // Keep the bits that select the instruction set
+        let mode = addr - code_addr;
+        let floor = code_addr.saturating_sub(MAX_BACKTRACK as u64 * min_len);
+        let floor = match self.functions.bounds(code_addr) {
+            Some(bounds) if min_len == max_len => floor.max(bounds.start),
+            // Variable length instructions can only be decoded from the start
+            // of one, and the start of the function is the only one known
+            Some(bounds) => bounds.start,
+            None if min_len == max_len => floor,
+            None => trace_bail!(
+                UnresolvedRegister,
+                "the function containing {:#x} is not known, so the instructions before it cannot be decoded",
+                addr
+            ),
+        };
+
+        let mut history = Vec::new();
+        let mut at = floor | mode;
+        while at < addr {
+            match self.load_ins(at) {
+                Ok(ins) => {
+                    at = ins.next();
+                    history.push(ins);
+                }
+                // Data such as a literal pool, which nothing before it can
+                // flow past
+                Err(_) => {
+                    at += min_len;
+                    history.clear();
+                }
+            }
+        }
+        if at != addr {
+            trace_bail!(
+                UnresolvedRegister,
+                "instructions decoded from {:#x} do not lead to the instruction at {:#x}",
+                floor,
+                addr
+            );
+        }
+        if history.len() > MAX_BACKTRACK {
+            history.drain(..history.len() - MAX_BACKTRACK);
+        }
+        Ok(history)
+    }
+
+    /// Reads the pointer at `index` in the pointer table occupying `table`.
+    fn table_entry(&self, table: Range<u64>, index: usize) -> TraceResult<u64> {
+        let pointer_size = self.memory.pointer_size();
+        let len = (table.end - table.start) / pointer_size;
+        if index as u64 >= len {
+            trace_bail!(
+                MissingStart,
+                "index {} is out of range for a table of {} pointers at {:#x}",
+                index,
+                len,
+                table.start
+            );
+        }
+        Ok(self
+            .memory
+            .read_pointer(table.start + index as u64 * pointer_size)?)
+    }
+
+    fn validate(&self, addr: u64) -> Validation {
+        let code_addr = self.arch.code_addr(addr);
+        let executable = self.memory.is_executable(code_addr);
+        let aligned = self.arch.is_aligned(addr);
+        let prologue = executable && aligned && self.load_ins(addr).is_ok_and(|ins| ins.prologue);
+        Validation {
+            executable,
+            aligned,
+            known_entry: self.functions.is_entry(code_addr),
+            prologue,
+        }
+    }
+
+    fn root<'s>(&'s self, method: &MethodRef<'s>) -> TraceResult<&'s Root> {
+        match self.roots.found.get(method) {
+            Some(Ok(root)) => Ok(root),
+            Some(Err(err)) => Err(err.clone()),
+            None => trace_bail!(
+                MissingRoot,
+                "could not find method '{}' in metadata",
+                method
+            ),
+        }
+    }
+
+    fn load_ins(&self, addr: u64) -> TraceResult<Insn> {
+        self.insns.decode(self.arch, self.memory, addr)
+    }
+}
+
+/// Whether `ins` is one of the instructions counted by `op`
+fn op_matches(op: TraceOp, ins: &Insn) -> bool {
+    let zero_matches = |filter: Option<ZeroCond>, zero: ZeroCond| filter.is_none_or(|f| f == zero);
+    match (op, ins.flow) {
+        (TraceOp::Call(_), Flow::Call(_))
+        | (TraceOp::Branch(_), Flow::Branch(_))
+        | (TraceOp::Indirect(_), Flow::Indirect { .. }) => true,
+        (TraceOp::Adrp(..), _) => ins.anchor.is_some(),
+        (TraceOp::CondBranch(filter, _), Flow::CondBranch(cond, _)) => {
+            filter.is_none_or(|f| f == cond)
+        }
+        (TraceOp::CompareBranch(filter, _), Flow::CompareBranch(zero, _))
+        | (TraceOp::TestBranch(filter, _), Flow::TestBranch(zero, _)) => zero_matches(filter, zero),
+        _ => false,
+    }
+}
+
+/// `value` with its high 16 bits replaced, as done by `MOVT`
+fn insert_high(value: u64, high: u16) -> u64 {
+    (value & 0xffff) | (high as u64) << 16
+}
+
+/// How far a trace got, kept up to date while walking
+#[derive(Default)]
+struct TraceState {
+    op_index: Option<usize>,
+    addr: Option<u64>,
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::arch::X86;
+    use crate::error::FailureKind;
+    use crate::memory::AddressSpace;
+    use std::collections::HashMap;
+
+    /// Follows `trace` from `start` over `segments`, which hold both code
+    /// and data, with `fdes` as the bounds of the functions in them. Returns
+    /// the address found or why the trace failed.
+    pub(crate) fn trace(
+        arch: Box<dyn InstructionSet>,
+        pointer_size: u64,
+        segments: &[(u64, &[u8])],
+        fdes: &[Range<u64>],
+        start: &str,
+        trace: &str,
+    ) -> Result<u64, FailureKind> {
+        let shared_object = SharedObject {
+            memory: RelocatedMemory::from_raw(
+                AddressSpace::from_raw(segments),
+                pointer_size,
+                HashMap::new(),
+                HashMap::new(),
+            ),
+            functions: FunctionMap::from_parts(fdes.to_vec(), Vec::new()),
+            starts: StartPoints::default(),
+            arch,
+            insns: InsnCache::default(),
+        };
+        let xref_data = XRefData {
+            traces: vec![SymbolTrace {
+                symbol: "symbol".to_owned(),
+                start: start.to_owned(),
+                trace: trace.to_owned(),
+            }],
+        };
+        let roots = Roots::unavailable(&xref_data, "no il2cpp metadata");
+        let output = XRefTracer::new(&shared_object, roots)
+            .trace_all(&xref_data)
+            .unwrap();
+        match (output.symbols.first(), output.failures.first()) {
+            (Some(symbol), _) => Ok(symbol.offset),
+            (None, Some(failure)) => Err(failure.kind),
+            (None, None) => panic!("trace neither succeeded nor failed"),
+        }
+    }
+
+    #[test]
+    fn caps_scans_outside_of_known_functions() {
+        // nop; ...; nop; call 0x1000
+        let len = MAX_UNBOUNDED_SCAN + 16;
+        let mut code = vec![0x90; len];
+        code.push(0xe8);
+        code.extend_from_slice(&(-(len as i32 + 5)).to_le_bytes());
+        let x86 = || Box::new(X86::new(64));
+        assert_eq!(
+            trace(x86(), 8, &[(0x1000, &code)], &[], "addr:0x1000", "L0"),
+            Err(FailureKind::FunctionOverrun)
+        );
+        let function = 0x1000..0x1000 + code.len() as u64;
+        assert_eq!(
+            trace(
+                x86(),
+                8,
+                &[(0x1000, &code)],
+                &[function],
+                "addr:0x1000",
+                "L0"
+            ),
+            Ok(0x1000)
+        );
+    }
+}
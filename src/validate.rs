@@ -0,0 +1,40 @@
+//! Sanity checks of the addresses traces resolve to.
+
+use serde::Serialize;
+
+/// The results of the checks run on the address a trace resolved to
+#[derive(Serialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Validation {
+    /// The address is in an executable segment
+    pub executable: bool,
+    /// The address is aligned for its instruction set: 4 bytes for AArch64
+    /// and ARM code, 2 bytes for Thumb code and always for x86
+    pub aligned: bool,
+    /// The address is a known function entry point: the start of an FDE or
+    /// an il2cpp method or invoker pointer
+    pub known_entry: bool,
+    /// The instruction at the address looks like the start of a prologue
+    pub prologue: bool,
+}
+
+impl Validation {
+    /// A score from 0 to 1 of how likely the address is to be right.
+    /// `may_be_data` is whether the trace is allowed to end outside of code,
+    /// which is only the case for traces ending in an address or pointer op.
+    pub fn confidence(&self, may_be_data: bool) -> f32 {
+        if !self.executable {
+            return if may_be_data { 0.5 } else { 0.0 };
+        }
+        if !self.aligned {
+            return 0.0;
+        }
+        let mut confidence = 0.5;
+        if self.known_entry {
+            confidence += 0.3;
+        }
+        if self.prologue {
+            confidence += 0.2;
+        }
+        confidence
+    }
+}